@@ -0,0 +1,136 @@
+use crate::xmp::find_sidecar;
+use crate::{read_metadata, FileMeta, RatingSource, XmpSearch};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+struct FileStamp {
+    modified: SystemTime,
+    size: u64,
+    sidecar_modified: Option<SystemTime>,
+}
+
+impl FileStamp {
+    fn read(filename: &Path) -> Result<FileStamp> {
+        let metadata = fs::metadata(filename)?;
+        let sidecar_modified = match find_sidecar(filename) {
+            Some(sidecar) => Some(fs::metadata(sidecar)?.modified()?),
+            None => None,
+        };
+        Ok(FileStamp {
+            modified: metadata.modified()?,
+            size: metadata.len(),
+            sidecar_modified,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct CachedMeta {
+    path: PathBuf,
+    stamp: FileStamp,
+    meta: FileMeta,
+}
+
+/// Bumped whenever [`FileMeta`] changes meaning, older cache files are discarded
+const CACHE_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize, Default)]
+struct CacheFile {
+    version: u32,
+    rating_tag: String,
+    prefer_sidecar: bool,
+    rating_source: RatingSource,
+    xmp_search: XmpSearch,
+    entries: Vec<CachedMeta>,
+}
+
+/// Metadata of previous runs keyed by path, reused while the file and its sidecar stay unchanged
+pub struct MetadataCache {
+    rating_tag: String,
+    prefer_sidecar: bool,
+    rating_source: RatingSource,
+    xmp_search: XmpSearch,
+    previous: HashMap<PathBuf, CachedMeta>,
+    current: Mutex<Vec<CachedMeta>>,
+}
+
+impl MetadataCache {
+    /// Loads the cache file, starting from scratch when it is missing, unreadable or was
+    /// written by another version or with different rating settings
+    pub fn load(
+        path: &Path,
+        rating_tag: &str,
+        prefer_sidecar: bool,
+        rating_source: RatingSource,
+        xmp_search: XmpSearch,
+    ) -> MetadataCache {
+        let cache_file: Option<CacheFile> = fs::read_to_string(path)
+            .ok()
+            .and_then(|data| serde_json::from_str(&data).ok());
+        let previous = match cache_file {
+            Some(cache_file)
+                if cache_file.version == CACHE_VERSION
+                    && cache_file.rating_tag == rating_tag
+                    && cache_file.prefer_sidecar == prefer_sidecar
+                    && cache_file.rating_source == rating_source
+                    && cache_file.xmp_search == xmp_search =>
+            {
+                cache_file
+                    .entries
+                    .into_iter()
+                    .map(|cached| (cached.path.clone(), cached))
+                    .collect()
+            }
+            _ => HashMap::new(),
+        };
+        MetadataCache {
+            rating_tag: rating_tag.to_string(),
+            prefer_sidecar,
+            rating_source,
+            xmp_search,
+            previous,
+            current: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Same as [`read_metadata`], but only reopens files that changed since they were cached
+    pub fn read_metadata(&self, filename: PathBuf) -> Result<FileMeta> {
+        let stamp = FileStamp::read(&filename)?;
+        let meta = match self.previous.get(&filename) {
+            Some(cached) if cached.stamp == stamp => cached.meta.clone(),
+            _ => read_metadata(
+                filename.clone(),
+                self.prefer_sidecar,
+                &self.rating_tag,
+                self.rating_source,
+                self.xmp_search,
+            )?,
+        };
+        self.current.lock().unwrap().push(CachedMeta {
+            path: filename,
+            stamp,
+            meta: meta.clone(),
+        });
+        Ok(meta)
+    }
+
+    /// Writes the files read during this run, dropping entries that weren't visited
+    pub fn save(self, path: &Path) -> Result<()> {
+        let cache_file = CacheFile {
+            version: CACHE_VERSION,
+            rating_tag: self.rating_tag,
+            prefer_sidecar: self.prefer_sidecar,
+            rating_source: self.rating_source,
+            xmp_search: self.xmp_search,
+            entries: self.current.into_inner().unwrap(),
+        };
+        fs::write(path, serde_json::to_string(&cache_file)?)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,1174 @@
+use crate::journal::{Journal, JournalOp};
+use crate::manifest::{Manifest, ManifestOp};
+use crate::{
+    clear_metadata, delete_gps, find_sidecars, get_rating, hash_file, rating_text, set_rating,
+    supports_clearing_metadata, write_label, write_rating, Entry, FileMeta, RatingOptions,
+};
+use anyhow::{anyhow, Result};
+use clap::{Subcommand, ValueEnum};
+use clap_complete::Shell;
+use filetime::FileTime;
+use log::{debug, error, info, warn};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::{fmt, fs, io};
+
+#[derive(Subcommand, PartialEq)]
+pub enum FileCommand {
+    Move,
+    Copy,
+    Delete,
+    Print,
+    DeleteRaws,
+    CopyRaws,
+    CopyRatingToRaws,
+    SetRating,
+    /// Write --set-label to `Xmp.xmp.Label`, an empty value removes the label
+    SetLabel,
+    /// Remove the GPS location tags from images
+    StripGps,
+    /// Revert the operations recorded in --journal
+    Undo,
+    /// Rename files to --name-template, in place or into --dest. Raw files and sidecars follow
+    /// the new name
+    Rename,
+    /// Print how many files have each rating and label
+    Stats,
+    /// Run the commands of a --dry-run output saved to --plan
+    ApplyPlan,
+    /// Print a completion script for the shell, to be redirected into its completion directory
+    #[command(hide = true)]
+    Completions {
+        shell: Shell,
+    },
+    /// Print the roff man page of every option and command
+    #[command(hide = true)]
+    Manpage,
+}
+
+impl Display for CollisionMode {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            CollisionMode::Skip => write!(f, "skip"),
+            CollisionMode::Rename => write!(f, "rename"),
+            CollisionMode::Overwrite => write!(f, "overwrite"),
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, PartialEq, Debug)]
+pub enum CollisionMode {
+    Skip,
+    Rename,
+    Overwrite,
+}
+
+/// How a run moves, copies and removes files, shared by every file it acts on
+pub struct CommandContext<'a> {
+    pub dry_run: bool,
+    pub on_collision: CollisionMode,
+    pub preserve_times: bool,
+    /// Only applied to copied images, never to their raws and sidecars
+    pub strip_metadata: bool,
+    pub verify: bool,
+    pub copy_sidecars: bool,
+    pub journal: Option<&'a mut Journal>,
+    pub manifest: Option<&'a mut Manifest>,
+}
+
+impl CommandContext<'_> {
+    /// The same options with another collision policy, recording into the same journal and
+    /// manifest
+    fn with_on_collision(&mut self, on_collision: CollisionMode) -> CommandContext<'_> {
+        CommandContext {
+            on_collision,
+            journal: self.journal.as_deref_mut(),
+            manifest: self.manifest.as_deref_mut(),
+            ..*self
+        }
+    }
+}
+
+/// Values written by the commands changing a file
+#[derive(Clone, Copy, Default, Debug)]
+pub struct CommandTargets<'a> {
+    pub rating: Option<i32>,
+    pub label: Option<&'a str>,
+    /// Stem every file of the entry gets renamed to
+    pub stem: Option<&'a str>,
+}
+
+/// Result of a successfully applied command
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Outcome {
+    Applied,
+    /// At least one file was left alone because its destination already exists
+    SkippedExisting,
+}
+
+impl Outcome {
+    fn merge(self, other: Outcome) -> Outcome {
+        match self {
+            Outcome::Applied => other,
+            Outcome::SkippedExisting => Outcome::SkippedExisting,
+        }
+    }
+}
+
+pub fn apply_command(
+    command: &FileCommand,
+    path: Entry,
+    destination_directory: Option<PathBuf>,
+    meta: &FileMeta,
+    targets: CommandTargets,
+    rating_options: &RatingOptions,
+    ctx: &mut CommandContext,
+) -> Result<Outcome> {
+    let dry_run = ctx.dry_run;
+    let mut outcome = Outcome::Applied;
+    let (primary_sidecar_paths, raw_sidecar_paths) = match ctx.copy_sidecars {
+        true => find_entry_sidecars(&path),
+        false => (Vec::new(), Vec::new()),
+    };
+    match command {
+        FileCommand::Move => {
+            let new_file_path = file_destination(destination_directory.as_deref(), &path.path)?;
+            outcome = outcome.merge(move_file(
+                path.path,
+                new_file_path,
+                ctx.strip_metadata,
+                ctx,
+            )?);
+            for raw_path in path.raw_paths {
+                let new_file_path = file_destination(destination_directory.as_deref(), &raw_path)?;
+                outcome = outcome.merge(move_file(raw_path, new_file_path, false, ctx)?);
+            }
+            for sidecar_path in primary_sidecar_paths.into_iter().chain(raw_sidecar_paths) {
+                let new_file_path =
+                    file_destination(destination_directory.as_deref(), &sidecar_path)?;
+                outcome = outcome.merge(move_file(sidecar_path, new_file_path, false, ctx)?);
+            }
+        }
+        FileCommand::Copy => {
+            let new_file_path = file_destination(destination_directory.as_deref(), &path.path)?;
+            outcome = outcome.merge(copy_file(
+                path.path,
+                new_file_path,
+                ctx.strip_metadata,
+                ctx,
+            )?);
+            for raw_path in path.raw_paths {
+                let new_file_path = file_destination(destination_directory.as_deref(), &raw_path)?;
+                outcome = outcome.merge(copy_file(raw_path, new_file_path, false, ctx)?);
+            }
+            for sidecar_path in primary_sidecar_paths.into_iter().chain(raw_sidecar_paths) {
+                let new_file_path =
+                    file_destination(destination_directory.as_deref(), &sidecar_path)?;
+                outcome = outcome.merge(copy_file(sidecar_path, new_file_path, false, ctx)?);
+            }
+        }
+        FileCommand::Delete => {
+            outcome = outcome.merge(discard_file(
+                path.path,
+                destination_directory.as_deref(),
+                ctx,
+            )?);
+            for raw_path in path.raw_paths {
+                outcome = outcome.merge(discard_file(
+                    raw_path,
+                    destination_directory.as_deref(),
+                    ctx,
+                )?);
+            }
+        }
+        FileCommand::Print => {
+            println!("{}", path.path.to_string_lossy());
+            for raw_path in path.raw_paths {
+                println!("{}", raw_path.to_string_lossy());
+            }
+        }
+        FileCommand::DeleteRaws => {
+            for raw_path in path.raw_paths {
+                outcome = outcome.merge(discard_file(
+                    raw_path,
+                    destination_directory.as_deref(),
+                    ctx,
+                )?);
+            }
+        }
+        FileCommand::CopyRaws => {
+            for raw_path in path.raw_paths {
+                let new_file_path = file_destination(destination_directory.as_deref(), &raw_path)?;
+                outcome = outcome.merge(copy_file(raw_path, new_file_path, false, ctx)?);
+            }
+            for sidecar_path in raw_sidecar_paths {
+                let new_file_path =
+                    file_destination(destination_directory.as_deref(), &sidecar_path)?;
+                outcome = outcome.merge(copy_file(sidecar_path, new_file_path, false, ctx)?);
+            }
+        }
+        FileCommand::CopyRatingToRaws => {
+            for raw_path in path.raw_paths {
+                copy_rating(path.path.clone(), raw_path, rating_options, ctx)?;
+            }
+        }
+        FileCommand::SetRating => {
+            let target_rating = targets.rating.expect("Did not specify rating to set");
+            update_rating(
+                path.path,
+                meta.rating,
+                target_rating,
+                &rating_options.rating_tag,
+                ctx,
+            )?;
+        }
+        FileCommand::SetLabel => {
+            let target_label = targets.label.expect("Did not specify label to set");
+            update_label(path.path, meta.label.as_deref(), target_label, ctx)?;
+        }
+        FileCommand::StripGps => {
+            strip_gps(path.path, ctx)?;
+        }
+        FileCommand::Rename => {
+            let new_stem = targets.stem.expect("Did not specify new file name");
+            let directory = destination_directory.as_deref();
+            let stems: Vec<String> = std::iter::once(&path.path)
+                .chain(&path.raw_paths)
+                .filter_map(|path| path.file_stem())
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .collect();
+            let paths: Vec<PathBuf> = std::iter::once(path.path)
+                .chain(path.raw_paths)
+                .chain(primary_sidecar_paths)
+                .chain(raw_sidecar_paths)
+                .collect();
+            let Some(new_stem) =
+                resolve_renamed_stem(&paths, &stems, directory, new_stem, &ctx.on_collision)
+            else {
+                if dry_run {
+                    println!(
+                        "# mv {} {} (skipped, destination exists)",
+                        shell_quote(&paths[0]),
+                        shell_quote(&renamed_path(&paths[0], &stems, directory, new_stem))
+                    );
+                }
+                return Ok(Outcome::SkippedExisting);
+            };
+            for path in paths {
+                let new_file_path = renamed_path(&path, &stems, directory, &new_stem);
+                if new_file_path == path {
+                    debug!("{:?} already has the new name", path);
+                    continue;
+                }
+                // Collisions were resolved for the whole entry so the files keep matching names
+                outcome = outcome.merge(move_file(
+                    path,
+                    new_file_path,
+                    false,
+                    &mut ctx.with_on_collision(CollisionMode::Overwrite),
+                )?);
+            }
+        }
+        FileCommand::Undo => {
+            anyhow::bail!("Undo replays the journal and is not applied to files");
+        }
+        FileCommand::Stats => {
+            anyhow::bail!("Stats only reads files and is not applied to them");
+        }
+        FileCommand::ApplyPlan => {
+            anyhow::bail!("Apply plan runs the plan file and is not applied to files");
+        }
+        FileCommand::Completions { .. } => {
+            anyhow::bail!("Completions only print a script and are not applied to files");
+        }
+        FileCommand::Manpage => {
+            anyhow::bail!("Manpage only prints the man page and is not applied to files");
+        }
+    }
+    Ok(outcome)
+}
+
+/// Sidecars of the primary file and of its raw files, a shared sidecar is only listed once
+fn find_entry_sidecars(path: &Entry) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let primary_sidecar_paths = find_sidecars(&path.path);
+    let mut raw_sidecar_paths: Vec<PathBuf> = Vec::new();
+    for sidecar_path in path
+        .raw_paths
+        .iter()
+        .flat_map(|raw_path| find_sidecars(raw_path))
+    {
+        if !primary_sidecar_paths.contains(&sidecar_path)
+            && !raw_sidecar_paths.contains(&sidecar_path)
+        {
+            raw_sidecar_paths.push(sidecar_path);
+        }
+    }
+    for sidecar_path in primary_sidecar_paths.iter().chain(&raw_sidecar_paths) {
+        debug!("Matched sidecar file {sidecar_path:?}");
+    }
+    (primary_sidecar_paths, raw_sidecar_paths)
+}
+
+/// Path of a file of the entry renamed to the new stem, the part of the name after the
+/// original stem (e.g. `.CR2` or `.jpg.xmp`) is kept. Without a directory the file stays in
+/// its own one, so raws from `--raw-src` aren't moved next to their images
+fn renamed_path(
+    path: &Path,
+    stems: &[String],
+    directory: Option<&Path>,
+    new_stem: &str,
+) -> PathBuf {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let suffix = stems
+        .iter()
+        .filter(|stem| file_name.starts_with(stem.as_str()))
+        .max_by_key(|stem| stem.len())
+        .map(|stem| file_name[stem.len()..].to_string())
+        .or_else(|| {
+            path.extension()
+                .map(|extension| format!(".{}", extension.to_string_lossy()))
+        })
+        .unwrap_or_default();
+    let file_name = format!("{new_stem}{suffix}");
+    match directory {
+        Some(directory) => directory.join(file_name),
+        None => path.with_file_name(file_name),
+    }
+}
+
+/// Picks the stem every file of the entry is renamed to. With the rename policy the stem gets
+/// the first " (n)" suffix free for all the files, `None` when the entry should be skipped
+fn resolve_renamed_stem(
+    paths: &[PathBuf],
+    stems: &[String],
+    directory: Option<&Path>,
+    new_stem: &str,
+    on_collision: &CollisionMode,
+) -> Option<String> {
+    let is_free = |stem: &str| {
+        paths.iter().all(|path| {
+            let new_file_path = renamed_path(path, stems, directory, stem);
+            new_file_path == *path || !new_file_path.exists()
+        })
+    };
+    if is_free(new_stem) {
+        return Some(new_stem.to_string());
+    }
+    match on_collision {
+        CollisionMode::Skip => {
+            debug!("Skipping {:?} as {:?} is already taken", paths[0], new_stem);
+            None
+        }
+        CollisionMode::Rename => {
+            let renamed_stem = (1..)
+                .map(|i| format!("{new_stem} ({i})"))
+                .find(|stem| is_free(stem))
+                .unwrap();
+            info!(
+                "Renaming {:?} to {:?} as {:?} is already taken",
+                paths[0], renamed_stem, new_stem
+            );
+            Some(renamed_stem)
+        }
+        CollisionMode::Overwrite => Some(new_stem.to_string()),
+    }
+}
+
+/// Runs the `mkdir -p`, `mv`, `cp` and `rm` lines of a dry-run plan with the same helpers as a
+/// regular run, commented out lines are skipped. Returns the number of failed lines
+pub fn apply_plan(path: &Path, ctx: &mut CommandContext) -> Result<usize> {
+    let plan = fs::read_to_string(path)?;
+    let mut failed_count = 0;
+    for (i, line) in plan.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let res = apply_plan_line(line, ctx);
+        if let Err(e) = res {
+            error!("Failed to apply line {} of the plan due to {e}", i + 1);
+            failed_count += 1;
+        }
+    }
+    Ok(failed_count)
+}
+
+fn apply_plan_line(line: &str, ctx: &mut CommandContext) -> Result<()> {
+    let words = split_shell_words(line)?;
+    let Some((command, args)) = words.split_first() else {
+        anyhow::bail!("Empty command");
+    };
+    match (command.to_str().unwrap_or_default(), args) {
+        ("mkdir", [flag, dir]) if flag == "-p" => match ctx.dry_run {
+            true => println!("mkdir -p {}", shell_quote(Path::new(dir))),
+            false => fs::create_dir_all(dir)?,
+        },
+        ("mv", [path, dest]) => {
+            move_file(Path::new(path), Path::new(dest), false, ctx)?;
+        }
+        ("cp", [path, dest]) => {
+            copy_file(Path::new(path), Path::new(dest), false, ctx)?;
+        }
+        ("rm", [path]) => remove_file(path, ctx)?,
+        _ => anyhow::bail!("Unsupported command {line:?}"),
+    }
+    Ok(())
+}
+
+/// Splits a line quoted by [`shell_quote`] into its words
+fn split_shell_words(line: &str) -> Result<Vec<OsString>> {
+    let mut words: Vec<OsString> = Vec::new();
+    let mut word: Option<Vec<u8>> = None;
+    let push_char =
+        |word: &mut Vec<u8>, c: char| word.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                let word = word.get_or_insert_with(Vec::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => push_char(word, c),
+                        None => anyhow::bail!("Unterminated quote in {line:?}"),
+                    }
+                }
+            }
+            // The `$'..'` quoting of names that aren't valid UTF-8
+            '$' if chars.peek() == Some(&'\'') => {
+                chars.next();
+                let word = word.get_or_insert_with(Vec::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('\\' | '\'')) => push_char(word, c),
+                            Some('x') => {
+                                let hex: String = chars.by_ref().take(2).collect();
+                                let byte = u8::from_str_radix(&hex, 16)
+                                    .map_err(|_| anyhow!("Invalid escape \\x{hex} in {line:?}"))?;
+                                word.push(byte);
+                            }
+                            _ => anyhow::bail!("Unsupported escape in {line:?}"),
+                        },
+                        Some(c) => push_char(word, c),
+                        None => anyhow::bail!("Unterminated quote in {line:?}"),
+                    }
+                }
+            }
+            '\\' => {
+                let escaped = chars
+                    .next()
+                    .ok_or_else(|| anyhow!("Trailing backslash in {line:?}"))?;
+                push_char(word.get_or_insert_with(Vec::new), escaped);
+            }
+            c if c.is_whitespace() => {
+                if let Some(word) = word.take() {
+                    words.push(os_string_from_bytes(word)?);
+                }
+            }
+            c => push_char(word.get_or_insert_with(Vec::new), c),
+        }
+    }
+    if let Some(word) = word {
+        words.push(os_string_from_bytes(word)?);
+    }
+    Ok(words)
+}
+
+/// Names that aren't valid UTF-8 can only be created on Unix
+fn os_string_from_bytes(bytes: Vec<u8>) -> Result<OsString> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt;
+        Ok(OsString::from_vec(bytes))
+    }
+    #[cfg(not(unix))]
+    {
+        Ok(String::from_utf8(bytes)?.into())
+    }
+}
+
+/// Path of the file with the same name inside the destination directory
+fn file_destination(destination_directory: Option<&Path>, path: &Path) -> Result<PathBuf> {
+    let destination_directory =
+        destination_directory.ok_or_else(|| anyhow!("Missing destination directory"))?;
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{:?} has no file name", path))?;
+    Ok(destination_directory.join(file_name))
+}
+
+/// Moves the file into the trash directory when one is given, removes it otherwise
+fn discard_file(
+    path: PathBuf,
+    trash_directory: Option<&Path>,
+    ctx: &mut CommandContext,
+) -> Result<Outcome> {
+    match trash_directory {
+        Some(trash_directory) => {
+            let new_file_path = file_destination(Some(trash_directory), &path)?;
+            move_file(path, new_file_path, false, ctx)
+        }
+        None => {
+            remove_file(path, ctx)?;
+            Ok(Outcome::Applied)
+        }
+    }
+}
+
+fn remove_file<P: AsRef<Path>>(path: P, ctx: &mut CommandContext) -> Result<()> {
+    match ctx.dry_run {
+        true => print_dry_run(
+            format!("rm {}", shell_quote(path.as_ref())),
+            check_preconditions(path.as_ref(), None, true),
+        )?,
+        false => {
+            info!("rm {:?}", path.as_ref());
+            fs::remove_file(path.as_ref())?;
+            if let Some(journal) = ctx.journal.as_deref_mut() {
+                journal.record(JournalOp::Delete, path.as_ref(), None)?;
+            }
+        }
+    }
+    if let Some(manifest) = ctx.manifest.as_deref_mut() {
+        manifest.record(ManifestOp::Delete, path.as_ref(), None, ctx.dry_run)?;
+    }
+    Ok(())
+}
+
+fn move_file<P: AsRef<Path>>(
+    path: P,
+    dest: P,
+    strip_metadata: bool,
+    ctx: &mut CommandContext,
+) -> Result<Outcome> {
+    let dry_run = ctx.dry_run;
+    let Some(dest) = resolve_collision(path.as_ref(), dest.as_ref(), &ctx.on_collision) else {
+        if dry_run {
+            println!(
+                "# mv {} {} (skipped, destination exists)",
+                shell_quote(path.as_ref()),
+                shell_quote(dest.as_ref())
+            );
+        }
+        return Ok(Outcome::SkippedExisting);
+    };
+    match dry_run {
+        true => {
+            print_dry_run(
+                format!("mv {} {}", shell_quote(path.as_ref()), shell_quote(&dest)),
+                check_preconditions(path.as_ref(), Some(&dest), true),
+            )?;
+            if strip_metadata {
+                strip_dest_metadata(&dest, dry_run)?;
+            }
+        }
+        false => {
+            info!("mv {:?} {:?}", path.as_ref(), dest);
+            match fs::rename(path.as_ref(), &dest) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                    debug!(
+                        "Falling back to copy and delete for cross-device move of {:?}",
+                        path.as_ref()
+                    );
+                    move_file_across_devices(path.as_ref(), &dest, ctx.verify)?;
+                }
+                Err(e) => return Err(e.into()),
+            }
+            // Moves keep the file times, writing the stripped file would update them
+            if strip_metadata {
+                let metadata = fs::metadata(&dest)?;
+                strip_dest_metadata(&dest, dry_run)?;
+                filetime::set_file_times(
+                    &dest,
+                    FileTime::from_last_access_time(&metadata),
+                    FileTime::from_last_modification_time(&metadata),
+                )?;
+            }
+        }
+    }
+    if let (false, Some(journal)) = (dry_run, ctx.journal.as_deref_mut()) {
+        journal.record(JournalOp::Move, path.as_ref(), Some(&dest))?;
+    }
+    if let Some(manifest) = ctx.manifest.as_deref_mut() {
+        manifest.record(ManifestOp::Move, path.as_ref(), Some(&dest), dry_run)?;
+    }
+    Ok(Outcome::Applied)
+}
+
+fn move_file_across_devices(path: &Path, dest: &Path, verify: bool) -> Result<()> {
+    let copy_res = fs::copy(path, dest).and_then(|copied_bytes| {
+        let source_bytes = fs::metadata(path)?.len();
+        if copied_bytes != source_bytes {
+            return Err(io::Error::other(format!(
+                "copied {copied_bytes} out of {source_bytes} bytes"
+            )));
+        }
+        Ok(())
+    });
+    if let Err(e) = copy_res {
+        // Leave the source untouched and clean up the partial copy
+        let _ = fs::remove_file(dest);
+        anyhow::bail!("Failed to copy {:?} to {:?}: {}", path, dest, e);
+    }
+    if verify {
+        verify_copy(path, dest)?;
+    }
+    // A rename keeps the timestamps, so the copy has to as well
+    copy_file_times(path, dest)?;
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+/// A copy whose content differs from its source
+#[derive(Debug)]
+pub struct ChecksumMismatch {
+    pub path: PathBuf,
+    pub dest: PathBuf,
+}
+
+impl Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Copy {:?} of {:?} is corrupted", self.dest, self.path)
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+/// Compares the content hashes of a copy and its source, a corrupted copy is removed
+fn verify_copy(path: &Path, dest: &Path) -> Result<()> {
+    if hash_file(path)? != hash_file(dest)? {
+        let _ = fs::remove_file(dest);
+        return Err(ChecksumMismatch {
+            path: path.to_path_buf(),
+            dest: dest.to_path_buf(),
+        }
+        .into());
+    }
+    debug!("Verified {:?}", dest);
+    Ok(())
+}
+
+fn copy_file<P: AsRef<Path>>(
+    path: P,
+    dest: P,
+    strip_metadata: bool,
+    ctx: &mut CommandContext,
+) -> Result<Outcome> {
+    let dry_run = ctx.dry_run;
+    let Some(dest) = resolve_collision(path.as_ref(), dest.as_ref(), &ctx.on_collision) else {
+        if dry_run {
+            println!(
+                "# cp {} {} (skipped, destination exists)",
+                shell_quote(path.as_ref()),
+                shell_quote(dest.as_ref())
+            );
+        }
+        return Ok(Outcome::SkippedExisting);
+    };
+    match dry_run {
+        true => {
+            print_dry_run(
+                format!("cp {} {}", shell_quote(path.as_ref()), shell_quote(&dest)),
+                check_preconditions(path.as_ref(), Some(&dest), false),
+            )?;
+            if strip_metadata {
+                strip_dest_metadata(&dest, dry_run)?;
+            }
+        }
+        false => {
+            info!("cp {:?} {:?}", path.as_ref(), dest);
+            fs::copy(path.as_ref(), &dest)?;
+            if ctx.verify {
+                verify_copy(path.as_ref(), &dest)?;
+            }
+            // Writing the stripped file updates its times, restore them afterwards
+            if strip_metadata {
+                strip_dest_metadata(&dest, dry_run)?;
+            }
+            if ctx.preserve_times {
+                copy_file_times(path.as_ref(), &dest)?;
+            }
+            if let Some(journal) = ctx.journal.as_deref_mut() {
+                journal.record(JournalOp::Copy, path.as_ref(), Some(&dest))?;
+            }
+        }
+    }
+    if let Some(manifest) = ctx.manifest.as_deref_mut() {
+        manifest.record(ManifestOp::Copy, path.as_ref(), Some(&dest), dry_run)?;
+    }
+    Ok(Outcome::Applied)
+}
+
+/// Clears EXIF, IPTC and XMP from a copied or moved file, formats exiv2 can't write are
+/// left untouched with a warning
+fn strip_dest_metadata(dest: &Path, dry_run: bool) -> Result<()> {
+    if !supports_clearing_metadata(dest) {
+        warn!("Keeping metadata of {dest:?}, stripping is only supported for JPEG, PNG, TIFF and WebP");
+        return Ok(());
+    }
+    match dry_run {
+        true => println!("# strip metadata: {}", shell_quote(dest)),
+        false => {
+            info!("strip metadata: {:?}", dest);
+            clear_metadata(dest)
+                .map_err(|e| anyhow!("Failed to strip metadata of {:?}: {}", dest, e))?;
+        }
+    }
+    Ok(())
+}
+
+fn copy_file_times(path: &Path, dest: &Path) -> Result<()> {
+    let metadata = fs::metadata(path)?;
+    filetime::set_file_times(
+        dest,
+        FileTime::from_last_access_time(&metadata),
+        FileTime::from_last_modification_time(&metadata),
+    )?;
+    Ok(())
+}
+
+/// Prints the shell command a real run would execute, commented out when it would fail so
+/// the dry-run output stays runnable
+fn print_dry_run(command: String, preconditions: Result<()>) -> Result<()> {
+    match preconditions {
+        Ok(()) => {
+            println!("{command}");
+            Ok(())
+        }
+        Err(e) => {
+            println!("# {command} (would fail: {e})");
+            Err(e)
+        }
+    }
+}
+
+/// Destination directories this run created, or printed a mkdir for in a dry run. Shared
+/// between threads so concurrent commands into the same directory create it once
+#[derive(Default)]
+pub struct DestinationDirs {
+    created: Mutex<HashSet<PathBuf>>,
+}
+
+impl DestinationDirs {
+    /// Creates the directory, a dry run prints the command creating it instead
+    pub fn create(&self, dir: &Path, dry_run: bool) -> io::Result<()> {
+        // Held while creating so a directory is only created and printed once
+        let mut created = self.created.lock().unwrap();
+        if created.contains(dir) || dir.exists() {
+            return Ok(());
+        }
+        match dry_run {
+            true => println!("mkdir -p {}", shell_quote(dir)),
+            false => {
+                info!("Creating destination directory: {dir:?}");
+                fs::create_dir_all(dir)?;
+            }
+        }
+        created.insert(dir.to_path_buf());
+        Ok(())
+    }
+}
+
+/// Quotes the path for POSIX shells. Names that aren't valid UTF-8 are written as `$'..'`
+/// with their other bytes escaped, which bash, zsh and ksh understand
+pub fn shell_quote(path: &Path) -> String {
+    let Some(path) = path.to_str() else {
+        let mut quoted = String::from("$'");
+        for &byte in path.as_os_str().as_encoded_bytes() {
+            match byte {
+                b'\\' | b'\'' => quoted.extend(['\\', byte.into()]),
+                b' '..=b'~' => quoted.push(byte.into()),
+                byte => quoted.push_str(&format!("\\x{byte:02x}")),
+            }
+        }
+        quoted.push('\'');
+        return quoted;
+    };
+    format!("'{}'", path.replace('\'', r"'\''"))
+}
+
+/// Checks what the real operation needs: a readable source, a writable destination directory
+/// and, when the source goes away, a writable source directory
+fn check_preconditions(path: &Path, dest: Option<&Path>, removes_source: bool) -> Result<()> {
+    let metadata = fs::metadata(path)?;
+    if removes_source {
+        // Unix only needs the directory to be writable, Windows refuses read-only files too
+        if cfg!(windows) && metadata.permissions().readonly() {
+            anyhow::bail!("{:?} is read-only", path);
+        }
+        check_writable_dir(path)?;
+    }
+    if let Some(dest) = dest {
+        check_writable_dir(dest)?;
+    }
+    Ok(())
+}
+
+fn check_writable_dir(path: &Path) -> Result<()> {
+    // Dry runs don't create destination directories, check the closest one that exists
+    let dir = path
+        .ancestors()
+        .skip(1)
+        .map(|dir| match dir.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => dir,
+        })
+        .find(|dir| dir.exists())
+        .unwrap_or(Path::new("."));
+    if fs::metadata(dir)?.permissions().readonly() {
+        anyhow::bail!("{:?} is read-only", dir);
+    }
+    Ok(())
+}
+
+fn resolve_collision(path: &Path, dest: &Path, on_collision: &CollisionMode) -> Option<PathBuf> {
+    if !dest.exists() {
+        return Some(dest.to_path_buf());
+    }
+    match on_collision {
+        CollisionMode::Skip => {
+            debug!("Skipping {:?} as {:?} it already exists", path, dest);
+            None
+        }
+        CollisionMode::Rename => {
+            let file_stem = dest.file_stem().unwrap_or_default();
+            let renamed_dest = (1..)
+                .map(|i| {
+                    let mut file_name = file_stem.to_os_string();
+                    file_name.push(format!(" ({i})"));
+                    if let Some(extension) = dest.extension() {
+                        file_name.push(".");
+                        file_name.push(extension);
+                    }
+                    dest.with_file_name(file_name)
+                })
+                .find(|renamed_dest| !renamed_dest.exists())
+                .unwrap();
+            info!(
+                "Renaming {:?} to {:?} as {:?} already exists",
+                path, renamed_dest, dest
+            );
+            Some(renamed_dest)
+        }
+        CollisionMode::Overwrite => {
+            info!("Overriding existing {:?} with {:?}", dest, path);
+            Some(dest.to_path_buf())
+        }
+    }
+}
+
+fn copy_rating(
+    path: PathBuf,
+    dest: PathBuf,
+    rating_options: &RatingOptions,
+    ctx: &mut CommandContext,
+) -> Result<()> {
+    let rating = match get_rating(
+        path.clone(),
+        rating_options.prefer_sidecar,
+        &rating_options.rating_tag,
+        rating_options.rating_source,
+        rating_options.xmp_search,
+    ) {
+        Ok(Some(rating)) => rating,
+        Ok(None) | Err(_) => {
+            if ctx.on_collision != CollisionMode::Overwrite {
+                debug!("Skipping {:?} as {:?} does not have rating", path, dest);
+                return Ok(());
+            } else {
+                info!("Removing rating from {:?}", dest);
+                0
+            }
+        }
+    };
+    match ctx.dry_run {
+        true => println!(
+            "# cp rating: {} {} -> {}",
+            rating,
+            shell_quote(&path),
+            shell_quote(&dest)
+        ),
+        false => {
+            info!("cp rating: {} {:?} -> {:?}", rating, path, dest);
+            set_rating(dest.clone(), rating)
+                .map_err(|e| anyhow!("Failed to write rating to {:?}: {}", dest, e))?;
+        }
+    }
+    if let Some(manifest) = ctx.manifest.as_deref_mut() {
+        manifest.record(ManifestOp::CopyRating, &path, Some(&dest), ctx.dry_run)?;
+    }
+    Ok(())
+}
+
+fn update_rating(
+    path: PathBuf,
+    rating: Option<i32>,
+    target_rating: i32,
+    rating_tag: &str,
+    ctx: &mut CommandContext,
+) -> Result<()> {
+    if rating == Some(target_rating) {
+        debug!(
+            "Skipping {:?} as it already has rating {}",
+            path, target_rating
+        );
+        return Ok(());
+    }
+    let rating = rating_text(rating);
+    match ctx.dry_run {
+        true => println!(
+            "# set rating: {} -> {} {}",
+            rating,
+            target_rating,
+            shell_quote(&path)
+        ),
+        false => {
+            info!("set rating: {} -> {} {:?}", rating, target_rating, path);
+            write_rating(path.clone(), rating_tag, target_rating)?;
+        }
+    }
+    if let Some(manifest) = ctx.manifest.as_deref_mut() {
+        manifest.record(ManifestOp::SetRating, &path, None, ctx.dry_run)?;
+    }
+    Ok(())
+}
+
+fn update_label(
+    path: PathBuf,
+    label: Option<&str>,
+    target_label: &str,
+    ctx: &mut CommandContext,
+) -> Result<()> {
+    let label = label.unwrap_or_default();
+    if label == target_label {
+        debug!("Skipping {:?} as it already has label {:?}", path, label);
+        return Ok(());
+    }
+    match ctx.dry_run {
+        true => println!(
+            "# set label: {:?} -> {:?} {}",
+            label,
+            target_label,
+            shell_quote(&path)
+        ),
+        false => {
+            info!("set label: {:?} -> {:?} {:?}", label, target_label, path);
+            write_label(path.clone(), target_label)?;
+        }
+    }
+    if let Some(manifest) = ctx.manifest.as_deref_mut() {
+        manifest.record(ManifestOp::SetLabel, &path, None, ctx.dry_run)?;
+    }
+    Ok(())
+}
+
+fn strip_gps(path: PathBuf, ctx: &mut CommandContext) -> Result<()> {
+    match ctx.dry_run {
+        true => println!("# strip gps: {}", shell_quote(&path)),
+        false => {
+            info!("strip gps: {:?}", path);
+            delete_gps(path.clone())?;
+        }
+    }
+    if let Some(manifest) = ctx.manifest.as_deref_mut() {
+        manifest.record(ManifestOp::StripGps, &path, None, ctx.dry_run)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context<'a>(on_collision: CollisionMode) -> CommandContext<'a> {
+        CommandContext {
+            dry_run: false,
+            on_collision,
+            preserve_times: false,
+            strip_metadata: false,
+            verify: false,
+            copy_sidecars: false,
+            journal: None,
+            manifest: None,
+        }
+    }
+
+    #[test]
+    fn split_shell_words_reverses_shell_quote() {
+        let path = Path::new("dir with space/it's $HOME.jpg");
+        let line = format!(
+            "mv {} {}",
+            shell_quote(path),
+            shell_quote(Path::new("dest"))
+        );
+        assert_eq!(
+            split_shell_words(&line).unwrap(),
+            vec!["mv", "dir with space/it's $HOME.jpg", "dest"]
+        );
+        assert!(split_shell_words("rm 'unterminated").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn split_shell_words_reverses_byte_escapes() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let path = Path::new(OsStr::from_bytes(b"caf\xe9 it's\\.jpg"));
+        let quoted = shell_quote(path);
+        assert_eq!(quoted, r"$'caf\xe9 it\'s\\.jpg'");
+        assert_eq!(
+            split_shell_words(&format!("rm {quoted}")).unwrap(),
+            vec![OsStr::new("rm"), path.as_os_str()]
+        );
+        assert!(split_shell_words(r"rm $'\xzz'").is_err());
+        assert!(split_shell_words(r"rm $'\n'").is_err());
+    }
+
+    #[test]
+    fn rename_keeps_raws_and_sidecars_matching() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths: Vec<PathBuf> = ["a.v2.jpg", "a.CR2", "a.v2.jpg.xmp", "a.xmp"]
+            .iter()
+            .map(|file| dir.path().join(file))
+            .collect();
+        let stems = vec!["a.v2".to_string(), "a".to_string()];
+        let renamed = |stem: &str| -> Vec<PathBuf> {
+            paths
+                .iter()
+                .map(|path| renamed_path(path, &stems, Some(dir.path()), stem))
+                .collect()
+        };
+        assert_eq!(
+            renamed("b"),
+            ["b.jpg", "b.CR2", "b.jpg.xmp", "b.xmp"]
+                .iter()
+                .map(|file| dir.path().join(file))
+                .collect::<Vec<PathBuf>>()
+        );
+
+        // A taken raw name moves the whole entry to the next free name
+        fs::write(dir.path().join("b.CR2"), b"").unwrap();
+        let resolve = |on_collision| {
+            resolve_renamed_stem(&paths, &stems, Some(dir.path()), "b", &on_collision)
+        };
+        assert_eq!(resolve(CollisionMode::Skip), None);
+        assert_eq!(resolve(CollisionMode::Rename), Some("b (1)".to_string()));
+        assert_eq!(resolve(CollisionMode::Overwrite), Some("b".to_string()));
+    }
+
+    #[test]
+    fn rename_in_place_keeps_raws_in_raw_src() {
+        let dir = tempfile::tempdir().unwrap();
+        let (images, raws) = (dir.path().join("images"), dir.path().join("raws"));
+        fs::create_dir_all(&images).unwrap();
+        fs::create_dir_all(&raws).unwrap();
+        for path in [images.join("a.jpg"), raws.join("a.CR2"), raws.join("a.xmp")] {
+            fs::write(path, b"").unwrap();
+        }
+
+        let entry = Entry::new_with_raw(images.join("a.jpg"), raws.join("a.CR2"));
+        let outcome = apply_command(
+            &FileCommand::Rename,
+            entry,
+            None,
+            &FileMeta::default(),
+            CommandTargets {
+                stem: Some("b"),
+                ..CommandTargets::default()
+            },
+            &RatingOptions {
+                prefer_sidecar: false,
+                rating_tag: crate::DEFAULT_RATING_TAG.to_string(),
+                rating_source: crate::RatingSource::Embedded,
+                xmp_search: crate::XmpSearch::default(),
+            },
+            &mut CommandContext {
+                copy_sidecars: true,
+                ..context(CollisionMode::Skip)
+            },
+        )
+        .unwrap();
+
+        assert_eq!(outcome, Outcome::Applied);
+        assert!(images.join("b.jpg").exists());
+        assert!(raws.join("b.CR2").exists());
+        assert!(raws.join("b.xmp").exists());
+        assert!(!images.join("b.CR2").exists());
+    }
+
+    #[test]
+    fn move_across_devices_keeps_timestamps() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.jpg");
+        let dest = dir.path().join("b.jpg");
+        fs::write(&path, "a").unwrap();
+        let mtime = FileTime::from_unix_time(1_500_000_000, 0);
+        filetime::set_file_times(&path, mtime, mtime).unwrap();
+
+        move_file_across_devices(&path, &dest, true).unwrap();
+        assert!(!path.exists());
+        let metadata = fs::metadata(&dest).unwrap();
+        assert_eq!(FileTime::from_last_modification_time(&metadata), mtime);
+    }
+
+    #[test]
+    fn move_with_strip_metadata_keeps_timestamps() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.mov");
+        let dest = dir.path().join("dest.mov");
+        fs::write(&path, "a").unwrap();
+        let mtime = FileTime::from_unix_time(1_500_000_000, 0);
+        filetime::set_file_times(&path, mtime, mtime).unwrap();
+
+        let outcome = move_file(&path, &dest, true, &mut context(CollisionMode::Skip)).unwrap();
+        assert_eq!(outcome, Outcome::Applied);
+        assert!(!path.exists());
+        let metadata = fs::metadata(&dest).unwrap();
+        assert_eq!(FileTime::from_last_modification_time(&metadata), mtime);
+    }
+
+    #[test]
+    fn concurrent_moves_into_one_destination() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("dest").join("nested");
+        let sources: Vec<PathBuf> = (0..64)
+            .map(|i| {
+                let path = dir.path().join(format!("{i}.jpg"));
+                fs::write(&path, i.to_string()).unwrap();
+                path
+            })
+            .collect();
+
+        let dest_dirs = DestinationDirs::default();
+        std::thread::scope(|scope| {
+            for chunk in sources.chunks(8) {
+                let (dest, dest_dirs) = (&dest, &dest_dirs);
+                scope.spawn(move || {
+                    for path in chunk {
+                        dest_dirs.create(dest, false).unwrap();
+                        let outcome = move_file(
+                            path.clone(),
+                            dest.join(path.file_name().unwrap()),
+                            false,
+                            &mut context(CollisionMode::Skip),
+                        )
+                        .unwrap();
+                        assert_eq!(outcome, Outcome::Applied);
+                    }
+                });
+            }
+        });
+
+        for (i, path) in sources.iter().enumerate() {
+            assert!(!path.exists());
+            let moved = dest.join(path.file_name().unwrap());
+            assert_eq!(fs::read_to_string(moved).unwrap(), i.to_string());
+        }
+    }
+}
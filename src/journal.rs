@@ -0,0 +1,201 @@
+use crate::commands::shell_quote;
+use anyhow::{anyhow, Result};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum JournalOp {
+    Move,
+    Copy,
+    Delete,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JournalRecord {
+    pub op: JournalOp,
+    pub source: PathBuf,
+    pub destination: Option<PathBuf>,
+}
+
+/// Append-only log of file operations, one JSON record per line
+pub struct Journal {
+    file: File,
+}
+
+impl Journal {
+    pub fn open(path: &Path) -> Result<Journal> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Journal { file })
+    }
+
+    pub fn record(
+        &mut self,
+        op: JournalOp,
+        source: &Path,
+        destination: Option<&Path>,
+    ) -> Result<()> {
+        let record = JournalRecord {
+            op,
+            source: source.to_path_buf(),
+            destination: destination.map(Path::to_path_buf),
+        };
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+        // Written in one call so an interrupted run leaves at most one partial line
+        self.file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+pub fn read_journal(path: &Path) -> Result<Vec<JournalRecord>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut records: Vec<JournalRecord> = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str(&line)
+            .map_err(|e| anyhow!("Invalid journal record on line {}: {}", i + 1, e))?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Sources of the journaled operations, for resuming an interrupted run. A missing journal
+/// has none, the partial record an interruption can leave on the last line is ignored
+pub fn journaled_sources(path: &Path) -> Result<HashSet<PathBuf>> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let data = String::from_utf8_lossy(&data);
+    let lines: Vec<(usize, &str)> = data
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .collect();
+    let mut sources: HashSet<PathBuf> = HashSet::new();
+    for (position, (i, line)) in lines.iter().enumerate() {
+        match serde_json::from_str::<JournalRecord>(line) {
+            Ok(record) => {
+                sources.insert(record.source);
+            }
+            Err(_) if position + 1 == lines.len() => {
+                warn!(
+                    "Ignoring the incomplete record on line {} of {:?}",
+                    i + 1,
+                    path
+                );
+            }
+            Err(e) => anyhow::bail!("Invalid journal record on line {}: {}", i + 1, e),
+        }
+    }
+    Ok(sources)
+}
+
+/// Reverts the journaled operations, newest first. Moves are moved back, copies are removed and
+/// deletions are reported as they can't be restored. Returns the number of failed records
+pub fn undo_journal(path: &Path, dry_run: bool) -> Result<usize> {
+    let mut failed_count = 0;
+    for record in read_journal(path)?.into_iter().rev() {
+        if let Err(e) = undo_record(&record, dry_run) {
+            error!(
+                "Failed to undo {:?} of {:?} due to {e}",
+                record.op, record.source
+            );
+            failed_count += 1;
+        }
+    }
+    Ok(failed_count)
+}
+
+fn undo_record(record: &JournalRecord, dry_run: bool) -> Result<()> {
+    match record.op {
+        JournalOp::Move => {
+            let destination = journaled_destination(record)?;
+            if record.source.exists() {
+                anyhow::bail!("{:?} already exists", record.source);
+            }
+            match dry_run {
+                true => println!(
+                    "mv {} {}",
+                    shell_quote(destination),
+                    shell_quote(&record.source)
+                ),
+                false => {
+                    info!("mv {:?} {:?}", destination, record.source);
+                    if let Some(parent) = record.source.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    match fs::rename(destination, &record.source) {
+                        Ok(()) => {}
+                        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                            fs::copy(destination, &record.source)?;
+                            fs::remove_file(destination)?;
+                        }
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+            }
+        }
+        JournalOp::Copy => {
+            let destination = journaled_destination(record)?;
+            match dry_run {
+                true => println!("rm {}", shell_quote(destination)),
+                false => {
+                    info!("rm {:?}", destination);
+                    fs::remove_file(destination)?;
+                }
+            }
+        }
+        JournalOp::Delete => {
+            anyhow::bail!("deleted files can't be restored");
+        }
+    }
+    Ok(())
+}
+
+fn journaled_destination(record: &JournalRecord) -> Result<&PathBuf> {
+    record
+        .destination
+        .as_ref()
+        .ok_or_else(|| anyhow!("Journal record is missing the destination"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn journaled_sources_ignore_incomplete_last_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.jsonl");
+        assert!(journaled_sources(&path).unwrap().is_empty());
+
+        let mut journal = Journal::open(&path).unwrap();
+        journal
+            .record(
+                JournalOp::Move,
+                Path::new("a.jpg"),
+                Some(Path::new("dest/a.jpg")),
+            )
+            .unwrap();
+        journal
+            .record(JournalOp::Delete, Path::new("b.jpg"), None)
+            .unwrap();
+        journal.file.write_all(b"{\"op\":\"copy\",\"sour").unwrap();
+
+        assert_eq!(
+            journaled_sources(&path).unwrap(),
+            HashSet::from([PathBuf::from("a.jpg"), PathBuf::from("b.jpg")])
+        );
+    }
+}
@@ -0,0 +1,2300 @@
+#![feature(trim_prefix_suffix)]
+
+use crate::cache::MetadataCache;
+use crate::quicktime::read_rating_quicktime;
+use crate::xmp::{
+    create_date_from_xmp, find_sidecar, flag_from_xmp, label_from_xmp, rating_from_xmp,
+    read_rating_xmp, read_sidecar, read_xmp, sidecar_rating, write_label_xmp, write_rating_xmp,
+};
+use anyhow::Result;
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta};
+use clap::ValueEnum;
+use exiftool::{ExifTool, ExifToolError};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use log::{debug, info, warn};
+use regex::Regex;
+use rexiv2::{Metadata, Orientation};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fmt::{Display, Formatter};
+use std::num::ParseIntError;
+use std::path::{Path, PathBuf};
+use std::{fmt, fs, io};
+
+pub mod cache;
+pub mod commands;
+pub mod journal;
+pub mod manifest;
+mod quicktime;
+pub mod rename;
+pub mod route;
+mod xmp;
+
+const IMAGE_EXTENSIONS: [&str; 9] = [
+    "avif", "heic", "jpg", "jpeg", "jxl", "png", "tif", "tiff", "webp",
+];
+const RAW_IMAGE_EXTENSIONS: [&str; 7] = ["arw", "dng", "cr2", "cr3", "nef", "raf", "orf"];
+const VIDEOS_EXTENSIONS: [&str; 3] = ["mov", "mp4", "avi"];
+/// Formats exiv2 can write, HEIC and raw files are read-only
+const CLEARABLE_METADATA_EXTENSIONS: [&str; 6] = ["jpg", "jpeg", "png", "tif", "tiff", "webp"];
+const IGNORE_FILE_NAME: &str = ".exifignore";
+const SIDECAR_EXTENSIONS: [&str; 3] = ["xmp", "pp3", "aae"];
+pub const DEFAULT_RATING_TAG: &str = "Xmp.xmp.Rating";
+pub const DEFAULT_XMP_TAIL_BYTES: usize = 4096 * 256;
+pub const DEFAULT_XMP_MAX_BYTES: usize = 4096 * 256;
+pub const DEFAULT_XMP_WHOLE_FILE_BYTES: usize = 4096 * 4096;
+const MICROSOFT_RATING_TAG: &str = "Xmp.MicrosoftPhoto.Rating";
+const LENS_TAGS: [&str; 3] = [
+    "Exif.Photo.LensModel",
+    "Exif.Canon.LensModel",
+    "Xmp.aux.Lens",
+];
+const ISO_TAG: &str = "Exif.Photo.ISOSpeedRatings";
+const FNUMBER_TAG: &str = "Exif.Photo.FNumber";
+const EXPOSURE_TIME_TAG: &str = "Exif.Photo.ExposureTime";
+
+impl Display for ComparisonCommand {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ComparisonCommand::MoreEqual => write!(f, "more-equal"),
+            ComparisonCommand::LessEqual => write!(f, "less-equal"),
+            ComparisonCommand::Equal => write!(f, "equal"),
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+pub enum ComparisonCommand {
+    MoreEqual,
+    LessEqual,
+    Equal,
+}
+
+impl Display for LabelMatch {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            LabelMatch::Any => write!(f, "any"),
+            LabelMatch::All => write!(f, "all"),
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+pub enum LabelMatch {
+    Any,
+    All,
+}
+
+impl Display for RatingSource {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            RatingSource::Embedded => write!(f, "embedded"),
+            RatingSource::Xmp => write!(f, "xmp"),
+            RatingSource::Sidecar => write!(f, "sidecar"),
+            RatingSource::Max => write!(f, "max"),
+            RatingSource::Min => write!(f, "min"),
+        }
+    }
+}
+
+/// Where the rating of a file is read from when its sources disagree
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, PartialEq, Default, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum RatingSource {
+    /// The tag read through exiv2, falling back to the embedded XMP packet and the sidecar
+    #[default]
+    Embedded,
+    /// Only the embedded XMP packet
+    Xmp,
+    /// Only the .xmp sidecar
+    Sidecar,
+    /// The highest rating of all sources
+    Max,
+    /// The lowest rating of all sources
+    Min,
+}
+
+impl RatingSource {
+    /// Picks from the rating of each source, [`RatingSource::Embedded`] takes the tag as is
+    fn select(self, tag: Option<i32>, xmp: Option<i32>, sidecar: Option<i32>) -> Option<i32> {
+        let ratings = [tag, xmp, sidecar].into_iter().flatten();
+        match self {
+            RatingSource::Embedded => tag,
+            RatingSource::Xmp => xmp,
+            RatingSource::Sidecar => sidecar,
+            RatingSource::Max => ratings.max(),
+            RatingSource::Min => ratings.min(),
+        }
+    }
+
+    /// Picks the pick flag of the embedded XMP or the sidecar, flags have no order so
+    /// [`RatingSource::Max`] and [`RatingSource::Min`] read them like
+    /// [`RatingSource::Embedded`]
+    fn select_flag(
+        self,
+        prefer_sidecar: bool,
+        embedded: Option<PickFlag>,
+        sidecar: Option<PickFlag>,
+    ) -> PickFlag {
+        let flag = match self {
+            RatingSource::Xmp => embedded,
+            RatingSource::Sidecar => sidecar,
+            _ if prefer_sidecar => sidecar.or(embedded),
+            _ => embedded.or(sidecar),
+        };
+        flag.unwrap_or_default()
+    }
+}
+
+/// How much of a file is scanned for an embedded XMP packet the metadata libraries
+/// don't surface, mostly in videos
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct XmpSearch {
+    /// Bytes at the end of the file searched first
+    pub tail_bytes: usize,
+    /// Bytes from the start of the file searched when the tail has no packet
+    pub max_bytes: usize,
+    /// Files up to this size are searched whole when neither the tail nor the start has
+    /// the packet
+    pub whole_file_bytes: usize,
+}
+
+impl Default for XmpSearch {
+    fn default() -> Self {
+        XmpSearch {
+            tail_bytes: DEFAULT_XMP_TAIL_BYTES,
+            max_bytes: DEFAULT_XMP_MAX_BYTES,
+            whole_file_bytes: DEFAULT_XMP_WHOLE_FILE_BYTES,
+        }
+    }
+}
+
+/// Where ratings are read from, the rating settings of [`Filter`] for the commands that
+/// read ratings themselves
+#[derive(Clone, Debug)]
+pub struct RatingOptions {
+    pub prefer_sidecar: bool,
+    pub rating_tag: String,
+    pub rating_source: RatingSource,
+    pub xmp_search: XmpSearch,
+}
+
+impl Display for RatingFrom {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            RatingFrom::Primary => write!(f, "primary"),
+            RatingFrom::Raw => write!(f, "raw"),
+        }
+    }
+}
+
+/// Which file of an entry the rating is read from
+#[derive(ValueEnum, Clone, Copy, PartialEq, Default, Debug)]
+pub enum RatingFrom {
+    #[default]
+    Primary,
+    /// The first matched raw file with a rating, falling back to the primary file
+    Raw,
+}
+
+impl Display for RawStem {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            RawStem::Full => write!(f, "full"),
+            RawStem::Base => write!(f, "base"),
+        }
+    }
+}
+
+/// Part of an image name a matching raw file has to share
+#[derive(ValueEnum, Clone, Copy, PartialEq, Default, Debug)]
+pub enum RawStem {
+    /// Everything before the last dot, `IMG_1.v2.jpg` matches `IMG_1.v2.CR2`
+    #[default]
+    Full,
+    /// Everything before the first dot, `IMG_1.v2.jpg` matches `IMG_1.CR2`
+    Base,
+}
+
+impl RawStem {
+    fn of(self, path: &Path) -> &OsStr {
+        let file_stem = path.file_stem().unwrap_or_default();
+        match (self, file_stem.to_str()) {
+            // Names that aren't valid UTF-8 keep their full stem
+            (RawStem::Base, Some(stem)) => match stem.split('.').next() {
+                Some(base) if !base.is_empty() => OsStr::new(base),
+                _ => file_stem,
+            },
+            _ => file_stem,
+        }
+    }
+}
+
+impl Display for MissingRating {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            MissingRating::Zero => write!(f, "zero"),
+            MissingRating::Skip => write!(f, "skip"),
+        }
+    }
+}
+
+/// How files without a rating tag are compared against the rating filter
+#[derive(ValueEnum, Clone, Copy, PartialEq, Default, Debug)]
+pub enum MissingRating {
+    /// Compare them as if they were rated 0
+    #[default]
+    Zero,
+    /// Never select them
+    Skip,
+}
+
+/// Pick or reject flag set by Lightroom and Bridge, stored as `xmpDM:pick`
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum PickFlag {
+    Pick,
+    Reject,
+    /// Also used for files without the flag
+    #[default]
+    Unflagged,
+}
+
+impl PickFlag {
+    /// Parses the `xmpDM:pick` value, 1 for picks and -1 for rejects
+    pub fn from_pick(value: &str) -> PickFlag {
+        match value.trim().parse() {
+            Ok(1) => PickFlag::Pick,
+            Ok(-1) => PickFlag::Reject,
+            _ => PickFlag::Unflagged,
+        }
+    }
+}
+
+impl Display for PickFlag {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            PickFlag::Pick => write!(f, "pick"),
+            PickFlag::Reject => write!(f, "reject"),
+            PickFlag::Unflagged => write!(f, "unflagged"),
+        }
+    }
+}
+
+/// Traversal options used by [`collect_entries`]
+///
+/// Included directories, skipped and matched files are logged through the `log` crate at
+/// the info and debug levels, which replace the former `verbose` field
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub src: PathBuf,
+    /// Directory raws are matched in instead of next to their images, mirroring the
+    /// layout of `src` unless `flat_raw_src` is set
+    pub raw_src: Option<PathBuf>,
+    pub flat_raw_src: bool,
+    /// Directory name substrings, or regular expressions when prefixed with `re:`
+    pub exclude: Vec<String>,
+    pub flip_exclusion: bool,
+    /// Apply `exclude` to directories at every depth instead of only the top level
+    pub exclude_recursive: bool,
+    pub include_videos: bool,
+    /// Select raw files as entries of their own, with `match_raws` only the raws
+    /// without a matching image
+    pub include_raw_only: bool,
+    /// Only select raw files without an image next to them they'd be matched to
+    pub orphan_raws: bool,
+    pub match_raws: bool,
+    pub raw_stem: RawStem,
+    /// Deepest directory level to descend into, `src` being level 0
+    pub max_depth: Option<i32>,
+    /// Abort the traversal once more than this many files are selected, see [`ScanLimitExceeded`]
+    pub max_scan: Option<usize>,
+    pub follow_symlinks: bool,
+    /// File name patterns to select, every file is selected when empty
+    pub include_glob: Vec<String>,
+    /// File name patterns to skip, these take precedence over `include_glob`
+    pub exclude_glob: Vec<String>,
+    /// Don't skip the paths listed in `.exifignore` files
+    pub no_ignore: bool,
+    /// Directory left out of the traversal, the destination when it is nested in `src`
+    pub skip_dir: Option<PathBuf>,
+    /// Set [`Entry::burst`] on files sharing a [`burst_key`] with another file
+    pub group_bursts: bool,
+    /// Image extensions to select instead of all supported ones
+    pub extensions: Vec<String>,
+    /// Extensions never selected, of images as well as videos and raws
+    pub exclude_extensions: Vec<String>,
+}
+
+/// Lowercased extensions from [`Config::extensions`] and [`Config::exclude_extensions`]
+struct ExtensionFilter {
+    images: Vec<String>,
+    excluded: Vec<String>,
+}
+
+impl ExtensionFilter {
+    fn new(config: &Config) -> Result<ExtensionFilter> {
+        let parse_all = |extensions: &[String], parse: fn(&str) -> Result<String, String>| {
+            extensions
+                .iter()
+                .map(|extension| parse(extension).map_err(anyhow::Error::msg))
+                .collect::<Result<Vec<String>>>()
+        };
+        Ok(ExtensionFilter {
+            images: parse_all(&config.extensions, parse_image_extension)?,
+            excluded: parse_all(&config.exclude_extensions, parse_extension)?,
+        })
+    }
+
+    fn allows_image(&self, extension: &str) -> bool {
+        self.images.is_empty() || self.images.iter().any(|image| image == extension)
+    }
+
+    fn excludes(&self, extension: &str) -> bool {
+        self.excluded.iter().any(|excluded| excluded == extension)
+    }
+}
+
+/// Parses a file extension such as `jpg` or `.JPG` to its lowercase form without the dot
+pub fn parse_extension(extension: &str) -> Result<String, String> {
+    let extension = extension.trim();
+    let extension = extension.strip_prefix('.').unwrap_or(extension);
+    if extension.is_empty() {
+        return Err("Extension can't be empty".into());
+    }
+    if extension.contains(['.', '/', '\\']) {
+        return Err(format!("Invalid extension {extension:?}"));
+    }
+    Ok(extension.to_lowercase())
+}
+
+/// Parses an extension like [`parse_extension`], rejecting those [`classify`] doesn't
+/// know as images since no file would be selected by them
+pub fn parse_image_extension(extension: &str) -> Result<String, String> {
+    let extension = parse_extension(extension)?;
+    match classify(Path::new(&format!("file.{extension}"))) {
+        MediaKind::Image => Ok(extension),
+        MediaKind::Video | MediaKind::Raw => Err(format!(
+            "{extension:?} isn't an image extension, videos and raws are selected with \
+             --include-videos and --include-raw-only"
+        )),
+        MediaKind::Other => Err(format!(
+            "Unsupported image extension {extension:?}, expected one of {}",
+            IMAGE_EXTENSIONS.join(", ")
+        )),
+    }
+}
+
+struct FileNameFilter {
+    include: GlobSet,
+    exclude: GlobSet,
+}
+
+impl FileNameFilter {
+    fn new(config: &Config) -> Result<FileNameFilter> {
+        Ok(FileNameFilter {
+            include: build_glob_set(&config.include_glob)?,
+            exclude: build_glob_set(&config.exclude_glob)?,
+        })
+    }
+
+    fn matches(&self, filename: &Path) -> bool {
+        let Some(file_name) = filename.file_name() else {
+            return false;
+        };
+        (self.include.is_empty() || self.include.is_match(file_name))
+            && !self.exclude.is_match(file_name)
+    }
+}
+
+fn build_glob_set(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+    Ok(builder.build()?)
+}
+
+/// Metadata criteria used by [`evaluate`]
+#[derive(Clone, Debug)]
+pub struct Filter {
+    pub threshold: i32,
+    pub comparison_command: ComparisonCommand,
+    /// Lower bound of the accepted rating range, supersedes `threshold`
+    pub min_rating: Option<i32>,
+    /// Upper bound of the accepted rating range, supersedes `threshold`
+    pub max_rating: Option<i32>,
+    /// Only pass files without a rating tag, supersedes the rating range and `threshold`
+    pub unrated: bool,
+    pub treat_missing_as: MissingRating,
+    /// Accepted labels, an empty list lets every file pass
+    pub label: Vec<String>,
+    pub label_match: LabelMatch,
+    /// Accepted pick flag, `None` lets every file pass
+    pub flag: Option<PickFlag>,
+    pub include_tag: Option<String>,
+    pub ignore_tag: Option<String>,
+    /// Inclusive lower bound of the capture date
+    pub after: Option<NaiveDate>,
+    /// Exclusive upper bound of the capture date
+    pub before: Option<NaiveDate>,
+    /// Let files without a capture date pass the date range check
+    pub include_undated: bool,
+    /// Inclusive lower bound of the file modification time
+    pub newer_than: Option<NaiveDateTime>,
+    /// Exclusive upper bound of the file modification time
+    pub older_than: Option<NaiveDateTime>,
+    /// Case-insensitive substring of the camera make or model
+    pub camera: Option<String>,
+    /// Case-insensitive substring of the lens model
+    pub lens: Option<String>,
+    /// Only pass images with an orientation flag other than normal
+    pub fix_orientation: bool,
+    /// Only pass files with (`true`) or without (`false`) GPS location data
+    pub has_gps: Option<bool>,
+    pub min_iso: Option<i32>,
+    pub max_iso: Option<i32>,
+    pub min_fnumber: Option<f64>,
+    pub max_fnumber: Option<f64>,
+    /// Shortest accepted exposure time in seconds
+    pub min_exposure_time: Option<f64>,
+    /// Longest accepted exposure time in seconds
+    pub max_exposure_time: Option<f64>,
+    /// Let files without the ISO, aperture or exposure time tag pass its range check
+    pub include_missing_exposure: bool,
+    /// Negates the whole filter, including the rating range
+    pub inverse: bool,
+    pub prefer_sidecar: bool,
+    pub rating_source: RatingSource,
+    pub rating_from: RatingFrom,
+    /// Tag the rating is read from, [`DEFAULT_RATING_TAG`] unless overridden
+    pub rating_tag: String,
+    pub xmp_search: XmpSearch,
+}
+
+impl Filter {
+    pub fn rating_options(&self) -> RatingOptions {
+        RatingOptions {
+            prefer_sidecar: self.prefer_sidecar,
+            rating_tag: self.rating_tag.clone(),
+            rating_source: self.rating_source,
+            xmp_search: self.xmp_search,
+        }
+    }
+
+    pub fn matches_camera(&self, camera: Option<&str>) -> bool {
+        matches_substring(self.camera.as_deref(), camera)
+    }
+
+    pub fn matches_lens(&self, lens: Option<&str>) -> bool {
+        matches_substring(self.lens.as_deref(), lens)
+    }
+
+    pub fn matches_modified(&self, filename: &Path) -> Result<bool> {
+        if self.newer_than.is_none() && self.older_than.is_none() {
+            return Ok(true);
+        }
+        let modified = get_modified_date(filename)?;
+        Ok(self
+            .newer_than
+            .is_none_or(|newer_than| modified >= newer_than)
+            && self
+                .older_than
+                .is_none_or(|older_than| modified < older_than))
+    }
+
+    pub fn matches_date(&self, capture_date: Option<NaiveDateTime>) -> bool {
+        if self.after.is_none() && self.before.is_none() {
+            return true;
+        }
+        let Some(capture_date) = capture_date else {
+            return self.include_undated;
+        };
+        let date = capture_date.date();
+        self.after.is_none_or(|after| date >= after)
+            && self.before.is_none_or(|before| date < before)
+    }
+
+    pub fn matches_orientation(&self, orientation: Option<i32>) -> bool {
+        if !self.fix_orientation {
+            return true;
+        }
+        let unrotated = [Orientation::Unspecified as i32, Orientation::Normal as i32];
+        orientation.is_some_and(|orientation| !unrotated.contains(&orientation))
+    }
+
+    pub fn matches_exposure(
+        &self,
+        iso: Option<i32>,
+        fnumber: Option<f64>,
+        exposure_time: Option<f64>,
+    ) -> bool {
+        self.matches_range(self.min_iso, self.max_iso, iso)
+            && self.matches_range(self.min_fnumber, self.max_fnumber, fnumber)
+            && self.matches_range(
+                self.min_exposure_time,
+                self.max_exposure_time,
+                exposure_time,
+            )
+    }
+
+    fn matches_range<T: PartialOrd>(
+        &self,
+        min: Option<T>,
+        max: Option<T>,
+        value: Option<T>,
+    ) -> bool {
+        if min.is_none() && max.is_none() {
+            return true;
+        }
+        let Some(value) = value else {
+            return self.include_missing_exposure;
+        };
+        min.is_none_or(|min| value >= min) && max.is_none_or(|max| value <= max)
+    }
+
+    pub fn matches_flag(&self, flag: PickFlag) -> bool {
+        self.flag.is_none_or(|expected| expected == flag)
+    }
+
+    pub fn matches_label(&self, label: Option<&str>) -> bool {
+        if self.label.is_empty() {
+            return true;
+        }
+        let Some(label) = label else {
+            return false;
+        };
+        match self.label_match {
+            LabelMatch::Any => self.label.iter().any(|expected| expected == label),
+            LabelMatch::All => self.label.iter().all(|expected| expected == label),
+        }
+    }
+
+    pub fn matches_rating(&self, rating: i32) -> bool {
+        match (self.min_rating, self.max_rating) {
+            (Some(min_rating), Some(max_rating)) => (min_rating..=max_rating).contains(&rating),
+            (Some(min_rating), None) => rating >= min_rating,
+            (None, Some(max_rating)) => rating <= max_rating,
+            (None, None) => match self.comparison_command {
+                ComparisonCommand::MoreEqual => rating >= self.threshold,
+                ComparisonCommand::LessEqual => rating <= self.threshold,
+                ComparisonCommand::Equal => rating == self.threshold,
+            },
+        }
+    }
+}
+
+fn matches_substring(pattern: Option<&str>, value: Option<&str>) -> bool {
+    let Some(pattern) = pattern else {
+        return true;
+    };
+    match value {
+        Some(value) => value.to_lowercase().contains(&pattern.to_lowercase()),
+        None => false,
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum MediaKind {
+    Image,
+    Video,
+    Raw,
+    Other,
+}
+
+/// Classifies a file by its case-insensitive extension, hidden files are always [`MediaKind::Other`]
+pub fn classify(path: &Path) -> MediaKind {
+    let is_hidden = path
+        .file_name()
+        .is_some_and(|file_name| file_name.to_string_lossy().starts_with('.'));
+    let Some(extension) = path.extension().and_then(OsStr::to_str) else {
+        return MediaKind::Other;
+    };
+    let extension = extension.to_lowercase();
+    match extension.as_str() {
+        _ if is_hidden => MediaKind::Other,
+        extension if IMAGE_EXTENSIONS.contains(&extension) => MediaKind::Image,
+        extension if VIDEOS_EXTENSIONS.contains(&extension) => MediaKind::Video,
+        extension if RAW_IMAGE_EXTENSIONS.contains(&extension) => MediaKind::Raw,
+        _ => MediaKind::Other,
+    }
+}
+
+/// Typed metadata failures, carried inside [`anyhow::Error`]
+#[derive(Debug)]
+pub enum MetadataError {
+    FileNotFound,
+    XmpNotFound(PathBuf),
+}
+
+impl Display for MetadataError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            MetadataError::FileNotFound => write!(f, "File doesn't exist"),
+            MetadataError::XmpNotFound(path) => write!(f, "XMP data not found in {:?}", path),
+        }
+    }
+}
+
+impl std::error::Error for MetadataError {}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorCategory {
+    Missing,
+    UnsupportedMetadata,
+    NoRating,
+}
+
+impl ErrorCategory {
+    pub fn of(error: &anyhow::Error) -> ErrorCategory {
+        if let Some(error) = error.downcast_ref::<MetadataError>() {
+            return match error {
+                MetadataError::FileNotFound => ErrorCategory::Missing,
+                MetadataError::XmpNotFound(_) => ErrorCategory::NoRating,
+            };
+        }
+        if let Some(error) = error.downcast_ref::<io::Error>() {
+            if error.kind() == io::ErrorKind::NotFound {
+                return ErrorCategory::Missing;
+            }
+        }
+        // A rating tag that isn't a number is as good as none
+        if error.downcast_ref::<ParseIntError>().is_some() {
+            return ErrorCategory::NoRating;
+        }
+        ErrorCategory::UnsupportedMetadata
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct FileMeta {
+    /// `None` when no rating source has a rating tag, unlike an explicit rating of 0
+    pub rating: Option<i32>,
+    pub label: Option<String>,
+    pub flag: PickFlag,
+    pub capture_date: Option<NaiveDateTime>,
+    /// Camera make and model joined with a space
+    pub camera: Option<String>,
+    pub lens: Option<String>,
+    /// Raw `Exif.Image.Orientation` value, only read for images
+    pub orientation: Option<i32>,
+    pub has_gps: bool,
+    pub iso: Option<i32>,
+    pub fnumber: Option<f64>,
+    /// Exposure time in seconds
+    pub exposure_time: Option<f64>,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub raw_paths: Vec<PathBuf>,
+    /// Burst the file is a frame of, only set with [`Config::group_bursts`]
+    pub burst: Option<PathBuf>,
+}
+
+impl Entry {
+    pub fn new(path: PathBuf) -> Entry {
+        Entry {
+            path,
+            raw_paths: Vec::new(),
+            burst: None,
+        }
+    }
+
+    pub fn new_with_raw(path: PathBuf, raw_path: PathBuf) -> Entry {
+        Entry {
+            path,
+            raw_paths: vec![raw_path],
+            burst: None,
+        }
+    }
+
+    pub fn new_with_raws(path: PathBuf, raw_paths: Vec<PathBuf>) -> Entry {
+        Entry {
+            path,
+            raw_paths,
+            burst: None,
+        }
+    }
+}
+
+impl Display for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(" p: {:?}", self.path))?;
+        for raw_path in &self.raw_paths {
+            f.write_fmt(format_args!("\n r: {:?}", raw_path))?;
+        }
+        Ok(())
+    }
+}
+
+/// More files were found than `Config::max_scan` allows, usually a `src` too high in the tree
+#[derive(Debug)]
+pub struct ScanLimitExceeded {
+    pub src: PathBuf,
+    pub limit: usize,
+}
+
+impl Display for ScanLimitExceeded {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Found more than {} files in {:?}, narrow the source path or raise --max-scan",
+            self.limit, self.src
+        )
+    }
+}
+
+impl std::error::Error for ScanLimitExceeded {}
+
+/// Entries found by [`traverse`]
+pub struct Traversal {
+    pub entries: Vec<Entry>,
+    /// Subdirectories left out because they couldn't be read, e.g. for lack of permissions
+    pub skipped_dirs: usize,
+}
+
+pub fn collect_entries(config: &Config) -> Result<Vec<Entry>> {
+    Ok(traverse(config)?.entries)
+}
+
+/// Same as [`collect_entries`], but also counts the unreadable subdirectories that were
+/// skipped. Only an unreadable `config.src` is an error
+pub fn traverse(config: &Config) -> Result<Traversal> {
+    let mut state = TraversalState {
+        entries: Vec::new(),
+        excluded_paths: config
+            .exclude
+            .iter()
+            .map(|pattern| ExcludePattern::new(pattern))
+            .collect::<Result<Vec<ExcludePattern>>>()?,
+        name_filter: FileNameFilter::new(config)?,
+        extension_filter: ExtensionFilter::new(config)?,
+        visited_dirs: HashSet::new(),
+        // A destination that doesn't exist yet has nothing to skip
+        skip_dir: config
+            .skip_dir
+            .as_ref()
+            .and_then(|skip_dir| fs::canonicalize(skip_dir).ok()),
+        skipped_dirs: 0,
+        ignores: Vec::new(),
+    };
+    if config.follow_symlinks {
+        state.visited_dirs.insert(fs::canonicalize(&config.src)?);
+    }
+    visit_dirs(config.src.as_ref(), 0, config, &mut state)?;
+    let TraversalState {
+        mut entries,
+        skipped_dirs,
+        ..
+    } = state;
+    if config.include_raw_only && config.match_raws {
+        drop_matched_raws(&mut entries);
+    }
+    if config.orphan_raws {
+        keep_orphan_raws(&mut entries, config.raw_stem);
+    }
+    if config.group_bursts {
+        mark_bursts(&mut entries);
+    }
+    Ok(Traversal {
+        entries,
+        skipped_dirs,
+    })
+}
+
+/// Builds entries from an explicit list of files instead of walking `config.src`.
+/// Files outside of `config.src` are skipped as the destination layout mirrors it
+pub fn entries_from_paths<I: IntoIterator<Item = PathBuf>>(
+    paths: I,
+    config: &Config,
+) -> Result<Vec<Entry>> {
+    let mut entries: Vec<Entry> = Vec::new();
+    let name_filter = FileNameFilter::new(config)?;
+    let extension_filter = ExtensionFilter::new(config)?;
+    for path in paths {
+        if !path.is_file() {
+            warn!("Skipping {path:?} as it is not a file");
+        } else if !path.starts_with(&config.src) {
+            warn!("Skipping {path:?} as it is outside of {:?}", config.src);
+        } else if !is_file_allowed(
+            &path,
+            config.include_videos,
+            config.include_raw_only || config.orphan_raws,
+            &extension_filter,
+        ) || !name_filter.matches(&path)
+        {
+            debug!("Skipping file {path:?}");
+        } else {
+            entries.push(build_entry(
+                path,
+                config.match_raws,
+                config.raw_stem,
+                config.raw_src.as_ref(),
+                config.flat_raw_src,
+                config.src.as_ref(),
+            ));
+        }
+    }
+    if config.include_raw_only && config.match_raws {
+        drop_matched_raws(&mut entries);
+    }
+    if config.orphan_raws {
+        keep_orphan_raws(&mut entries, config.raw_stem);
+    }
+    if config.group_bursts {
+        mark_bursts(&mut entries);
+    }
+    Ok(entries)
+}
+
+/// Burst a file belongs to by its name, `<base>_<counter>` or `<base>-<counter>` where the
+/// base ends with a digit and the counter has at most 3 digits, e.g. `IMG_1234_001.jpg` and
+/// `DSC01234-2.jpg`. Plain sequential names such as `IMG_1234.jpg` and date-time names such
+/// as `IMG_20240703_120000.jpg` aren't treated as bursts
+pub fn burst_key(path: &Path) -> Option<PathBuf> {
+    let stem = path.file_stem()?.to_str()?;
+    let base = stem.trim_end_matches(|c: char| c.is_ascii_digit());
+    if !(1..=3).contains(&(stem.len() - base.len())) {
+        return None;
+    }
+    let base = base.strip_suffix(['_', '-'])?;
+    let number = base.len() - base.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+    // An 8 digit number is the date of Android and Pixel names, not a frame sequence
+    if number == 0 || number == 8 {
+        return None;
+    }
+    Some(path.with_file_name(base))
+}
+
+/// Removes the raw entries that are already matched to an image
+fn drop_matched_raws(entries: &mut Vec<Entry>) {
+    let matched_raws: HashSet<PathBuf> = entries
+        .iter()
+        .flat_map(|entry| entry.raw_paths.iter().cloned())
+        .collect();
+    entries.retain(|entry| !matched_raws.contains(&entry.path));
+}
+
+/// Keeps the raw entries without an image they'd be matched to. Every image in the
+/// directory counts, including the ones the extension and name filters leave out, so a
+/// raw is never reported as an orphan because its image wasn't selected
+fn keep_orphan_raws(entries: &mut Vec<Entry>, raw_stem: RawStem) {
+    // Stems of the images in each directory, `None` when the directory can't be read
+    let mut image_stems: HashMap<PathBuf, Option<HashSet<OsString>>> = HashMap::new();
+    entries.retain(|entry| {
+        if classify(&entry.path) != MediaKind::Raw {
+            return false;
+        }
+        let Some(dir) = entry.path.parent() else {
+            return false;
+        };
+        let stems =
+            image_stems
+                .entry(dir.to_path_buf())
+                .or_insert_with(|| match read_dir_paths(dir) {
+                    Ok(paths) => Some(
+                        paths
+                            .iter()
+                            .filter(|path| classify(path) == MediaKind::Image)
+                            .flat_map(|path| {
+                                let stem = raw_stem.of(path);
+                                // Edited copies such as `IMG_1_c.jpg` belong to `IMG_1.CR2`
+                                let base_stem = stem.to_str().map(|stem| stem.trim_suffix("_c"));
+                                [Some(stem.to_owned()), base_stem.map(OsString::from)]
+                            })
+                            .flatten()
+                            .collect(),
+                    ),
+                    Err(e) => {
+                        warn!("Unable to check {dir:?} for images matching its raws due to {e}");
+                        None
+                    }
+                });
+        let stem = entry.path.file_stem().unwrap_or_default();
+        stems.as_ref().is_some_and(|stems| !stems.contains(stem))
+    });
+}
+
+/// Sets the burst of entries sharing a burst key, a single frame is not a burst
+fn mark_bursts(entries: &mut [Entry]) {
+    let keys: Vec<Option<PathBuf>> = entries.iter().map(|entry| burst_key(&entry.path)).collect();
+    let mut frame_counts: HashMap<&Path, usize> = HashMap::new();
+    for key in keys.iter().flatten() {
+        *frame_counts.entry(key).or_default() += 1;
+    }
+    for (entry, key) in entries.iter_mut().zip(&keys) {
+        entry.burst = key.clone().filter(|key| frame_counts[key.as_path()] > 1);
+    }
+}
+
+pub fn evaluate(entry: &Entry, filter: &Filter) -> Result<bool> {
+    Ok(evaluate_with_meta(entry, filter)?.is_some())
+}
+
+/// Same as [`evaluate`], but hands back the metadata of a passing entry
+pub fn evaluate_with_meta(entry: &Entry, filter: &Filter) -> Result<Option<FileMeta>> {
+    let meta = read_metadata(
+        entry.path.clone(),
+        filter.prefer_sidecar,
+        &filter.rating_tag,
+        filter.rating_source,
+        filter.xmp_search,
+    )?;
+    let meta = with_raw_rating(entry, filter, meta, |raw_path| {
+        read_metadata(
+            raw_path,
+            filter.prefer_sidecar,
+            &filter.rating_tag,
+            filter.rating_source,
+            filter.xmp_search,
+        )
+    });
+    evaluate_meta(entry, filter, meta)
+}
+
+/// Same as [`evaluate_with_meta`], but reads the metadata through the cache
+pub fn evaluate_cached(
+    entry: &Entry,
+    filter: &Filter,
+    cache: &MetadataCache,
+) -> Result<Option<FileMeta>> {
+    let meta = cache.read_metadata(entry.path.clone())?;
+    let meta = with_raw_rating(entry, filter, meta, |raw_path| {
+        cache.read_metadata(raw_path)
+    });
+    evaluate_meta(entry, filter, meta)
+}
+
+/// Replaces the rating with the one of the first rated raw file when rating from raws,
+/// unreadable and unrated raws leave the rating of the primary file
+fn with_raw_rating(
+    entry: &Entry,
+    filter: &Filter,
+    mut meta: FileMeta,
+    read_metadata: impl Fn(PathBuf) -> Result<FileMeta>,
+) -> FileMeta {
+    if filter.rating_from == RatingFrom::Raw {
+        let raw_rating =
+            entry
+                .raw_paths
+                .iter()
+                .find_map(|raw_path| match read_metadata(raw_path.clone()) {
+                    Ok(raw_meta) => raw_meta.rating,
+                    Err(e) => {
+                        debug!("Failed to read rating of {raw_path:?} due to {e}");
+                        None
+                    }
+                });
+        if raw_rating.is_some() {
+            meta.rating = raw_rating;
+        }
+    }
+    meta
+}
+
+fn evaluate_meta(entry: &Entry, filter: &Filter, meta: FileMeta) -> Result<Option<FileMeta>> {
+    let pass_label_check = filter.matches_label(meta.label.as_deref());
+
+    let pass_flag_check = filter.matches_flag(meta.flag);
+
+    let pass_include_label_check = if let Some(ref include_tag) = filter.include_tag {
+        match get_tags(entry.path.clone())? {
+            Some(tags_res) => tags_res.contains(&include_tag.to_lowercase()),
+            None => false,
+        }
+    } else {
+        true
+    };
+
+    let pass_ignore_label_check = if let Some(ref ignore_tag) = filter.ignore_tag {
+        match get_tags(entry.path.clone())? {
+            Some(tags_res) => !tags_res.contains(&ignore_tag.to_lowercase()),
+            None => false,
+        }
+    } else {
+        true
+    };
+
+    let pass_treshold_check = match (filter.unrated, meta.rating) {
+        (true, rating) => rating.is_none(),
+        (false, Some(rating)) => filter.matches_rating(rating),
+        (false, None) => match filter.treat_missing_as {
+            MissingRating::Zero => filter.matches_rating(0),
+            MissingRating::Skip => false,
+        },
+    };
+
+    let pass_date_check = filter.matches_date(meta.capture_date);
+
+    let pass_modified_check = filter.matches_modified(&entry.path)?;
+
+    let pass_camera_check = filter.matches_camera(meta.camera.as_deref());
+
+    let pass_lens_check = filter.matches_lens(meta.lens.as_deref());
+
+    let pass_orientation_check = filter.matches_orientation(meta.orientation);
+
+    let pass_gps_check = filter.has_gps.is_none_or(|has_gps| has_gps == meta.has_gps);
+
+    let pass_exposure_check = filter.matches_exposure(meta.iso, meta.fnumber, meta.exposure_time);
+
+    let mut should_move = pass_treshold_check
+        && pass_date_check
+        && pass_modified_check
+        && pass_camera_check
+        && pass_lens_check
+        && pass_orientation_check
+        && pass_gps_check
+        && pass_exposure_check
+        && pass_label_check
+        && pass_flag_check
+        && pass_ignore_label_check
+        && pass_include_label_check;
+
+    if filter.inverse {
+        should_move = !should_move;
+    }
+
+    Ok(should_move.then_some(meta))
+}
+
+/// Filters and results shared by the directories [`visit_dirs`] walks
+struct TraversalState {
+    entries: Vec<Entry>,
+    excluded_paths: Vec<ExcludePattern>,
+    name_filter: FileNameFilter,
+    extension_filter: ExtensionFilter,
+    /// Canonical directories already walked, only tracked when following symlinks
+    visited_dirs: HashSet<PathBuf>,
+    skip_dir: Option<PathBuf>,
+    skipped_dirs: usize,
+    /// Ignore files of the directories being walked, outermost first
+    ignores: Vec<Gitignore>,
+}
+
+fn visit_dirs(
+    dir: &Path,
+    depth: i32,
+    config: &Config,
+    state: &mut TraversalState,
+) -> io::Result<()> {
+    if dir.is_dir() {
+        let dir_paths = match read_dir_paths(dir) {
+            Ok(dir_paths) => dir_paths,
+            // A single unreadable folder of a shared drive shouldn't abort the whole scan
+            Err(e) if depth > 0 => {
+                warn!("Skipping directory {dir:?} due to {e}");
+                state.skipped_dirs += 1;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        // Patterns of an ignore file apply to the whole subtree of its directory, so it's
+        // popped once the directory is walked. Errors abort the whole traversal instead
+        let ignore_file = dir.join(IGNORE_FILE_NAME);
+        let has_ignore_file = !config.no_ignore && ignore_file.is_file();
+        if has_ignore_file {
+            let mut builder = GitignoreBuilder::new(dir);
+            if let Some(e) = builder.add(&ignore_file) {
+                warn!("Failed to parse {ignore_file:?} due to {e}");
+            }
+            let ignore = builder.build().unwrap_or_else(|e| {
+                warn!("Failed to parse {ignore_file:?} due to {e}");
+                Gitignore::empty()
+            });
+            state.ignores.push(ignore);
+        }
+        for path in dir_paths {
+            if !config.follow_symlinks {
+                match fs::symlink_metadata(&path) {
+                    Ok(metadata) if metadata.is_symlink() => {
+                        debug!("Skipping symlink {path:?}");
+                        continue;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn!("Skipping {path:?} due to {e}");
+                        continue;
+                    }
+                }
+            }
+            if is_ignored(&state.ignores, &path) {
+                debug!("Skipping ignored {path:?}");
+                continue;
+            }
+            if path.is_dir() {
+                let canonical_path = match fs::canonicalize(&path) {
+                    Ok(canonical_path) => canonical_path,
+                    Err(e) => {
+                        warn!("Skipping directory {path:?} due to {e}");
+                        state.skipped_dirs += 1;
+                        continue;
+                    }
+                };
+                // Symlinks can point back up the tree, only enter each directory once
+                if config.follow_symlinks && !state.visited_dirs.insert(canonical_path.clone()) {
+                    debug!("Skipping already visited directory {path:?}");
+                    continue;
+                }
+                // Files already moved into the destination must not be processed again
+                if state.skip_dir.as_ref() == Some(&canonical_path) {
+                    debug!("Skipping destination directory {path:?}");
+                    continue;
+                }
+                let dir_name = path
+                    .as_path()
+                    .file_name()
+                    .expect("Could not get relative path")
+                    .to_string_lossy();
+                let mut filter_res = filter_string(&dir_name, &state.excluded_paths);
+                if config.flip_exclusion {
+                    filter_res = !filter_res;
+                }
+                // A flipped exclusion selects top level directories, their contents are kept whole
+                let check_exclusion =
+                    depth == 0 || (config.exclude_recursive && !config.flip_exclusion);
+                let within_depth = config.max_depth.is_none_or(|max_depth| depth < max_depth);
+                if (!check_exclusion || filter_res) && !dir_name.starts_with(".") && within_depth {
+                    // filter
+                    if depth == 0 {
+                        info!("Including {dir_name}");
+                    }
+                    visit_dirs(&path, depth + 1, config, state)?;
+                }
+            } else if is_file_allowed(
+                &path,
+                config.include_videos,
+                config.include_raw_only || config.orphan_raws,
+                &state.extension_filter,
+            ) && state.name_filter.matches(&path)
+            {
+                state.entries.push(build_entry(
+                    path,
+                    config.match_raws,
+                    config.raw_stem,
+                    config.raw_src.as_ref(),
+                    config.flat_raw_src,
+                    config.src.as_ref(),
+                ));
+                if let Some(limit) = config.max_scan.filter(|limit| state.entries.len() > *limit) {
+                    return Err(io::Error::other(ScanLimitExceeded {
+                        src: config.src.clone(),
+                        limit,
+                    }));
+                }
+            } else {
+                debug!("Skipping file {path:?}");
+            }
+        }
+        if has_ignore_file {
+            state.ignores.pop();
+        }
+    }
+    Ok(())
+}
+
+/// Paths in the directory sorted, as read_dir order is arbitrary and runs and collision
+/// renames should be reproducible. Unreadable entries are skipped
+fn read_dir_paths(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut dir_paths: Vec<PathBuf> = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        match entry {
+            Ok(entry) => dir_paths.push(entry.path()),
+            Err(e) => warn!("Skipping an entry of {dir:?} due to {e}"),
+        }
+    }
+    dir_paths.sort();
+    Ok(dir_paths)
+}
+
+/// The innermost ignore file with a matching pattern decides, `!` patterns re-include
+fn is_ignored(ignores: &[Gitignore], path: &Path) -> bool {
+    for ignore in ignores.iter().rev() {
+        match ignore.matched(path, path.is_dir()) {
+            Match::Ignore(_) => return true,
+            Match::Whitelist(_) => return false,
+            Match::None => {}
+        }
+    }
+    false
+}
+
+fn build_entry(
+    path_buf: PathBuf,
+    raws_matched: bool,
+    raw_stem: RawStem,
+    raw_path: Option<&PathBuf>,
+    flat_raw_path: bool,
+    search_dir: &Path,
+) -> Entry {
+    let (raw_dir_path, raw_file_stem) = match raw_path {
+        Some(raw_base_path) => {
+            let dir_path = match flat_raw_path {
+                true => raw_base_path.clone(),
+                false => {
+                    // Calculate new relative path within raw directory
+                    let relative_path = path_buf.strip_prefix(search_dir).expect(
+                        format!("Failed to strip root prefix of file {:?}", path_buf).as_str(),
+                    );
+                    let new_file_path = raw_base_path.join(&relative_path);
+                    new_file_path.parent().unwrap().to_path_buf()
+                }
+            };
+
+            let file_stem = raw_stem.of(&path_buf);
+            // Names that aren't valid UTF-8 are matched without trimming the suffix
+            let file_stem = match file_stem.to_str() {
+                Some(file_stem) => OsStr::new(file_stem.trim_suffix("_c")),
+                None => file_stem,
+            };
+
+            (dir_path, file_stem)
+        }
+        None => (
+            path_buf.parent().unwrap().to_path_buf(),
+            raw_stem.of(&path_buf),
+        ),
+    };
+    // A selected raw is never matched to other raws
+    let raw_file_paths = if raws_matched && classify(&path_buf) != MediaKind::Raw {
+        find_raw_files(&raw_dir_path, raw_file_stem)
+    } else {
+        Vec::new()
+    };
+    for raw_file_path in &raw_file_paths {
+        debug!("Matched raw file {raw_file_path:?}");
+    }
+    match raw_file_paths.len() {
+        0 => Entry::new(path_buf),
+        1 => Entry::new_with_raw(path_buf, raw_file_paths.into_iter().next().unwrap()),
+        _ => Entry::new_with_raws(path_buf, raw_file_paths),
+    }
+}
+
+fn find_raw_files(dir: &Path, file_stem: &OsStr) -> Vec<PathBuf> {
+    let mut raw_file_paths: Vec<PathBuf> = Vec::new();
+    for raw_extension in RAW_IMAGE_EXTENSIONS {
+        // Case-insensitive filesystems report every variant, only keep the first match
+        let title_case = raw_extension[..1].to_uppercase() + &raw_extension[1..];
+        for extension in [
+            raw_extension.to_uppercase(),
+            raw_extension.to_string(),
+            title_case,
+        ] {
+            let mut raw_file_name = file_stem.to_os_string();
+            raw_file_name.push(format!(".{extension}"));
+            let raw_file_path = dir.join(raw_file_name);
+            if raw_file_path.exists() {
+                raw_file_paths.push(raw_file_path);
+                break;
+            }
+        }
+    }
+    raw_file_paths
+}
+
+enum ExcludePattern {
+    Substring(String),
+    Regex(Regex),
+}
+
+impl ExcludePattern {
+    /// Values prefixed with `re:` are regular expressions, anything else is a substring
+    fn new(pattern: &str) -> Result<ExcludePattern> {
+        Ok(match pattern.strip_prefix("re:") {
+            Some(regex) => ExcludePattern::Regex(Regex::new(regex)?),
+            None => ExcludePattern::Substring(pattern.to_string()),
+        })
+    }
+
+    fn matches(&self, string: &str) -> bool {
+        match self {
+            ExcludePattern::Substring(substring) => string.contains(substring.as_str()),
+            ExcludePattern::Regex(regex) => regex.is_match(string),
+        }
+    }
+}
+
+fn filter_string(string: &str, excluded_paths: &[ExcludePattern]) -> bool {
+    for path in excluded_paths {
+        if path.matches(string) {
+            return false;
+        }
+    }
+    true
+}
+
+/// Editor sidecars named either `<stem>.<ext>` or `<name>.<ext>.<sidecar ext>`
+pub fn find_sidecars(filename: &Path) -> Vec<PathBuf> {
+    let mut sidecar_paths: Vec<PathBuf> = Vec::new();
+    for sidecar_extension in SIDECAR_EXTENSIONS {
+        for full_name in [true, false] {
+            // Case-insensitive filesystems report both variants, only keep the first match
+            for extension in [
+                sidecar_extension.to_lowercase(),
+                sidecar_extension.to_uppercase(),
+            ] {
+                let sidecar_path = match full_name {
+                    true => {
+                        let mut sidecar_path = filename.as_os_str().to_owned();
+                        sidecar_path.push(format!(".{extension}"));
+                        PathBuf::from(sidecar_path)
+                    }
+                    false => filename.with_extension(&extension),
+                };
+                if sidecar_path.is_file() {
+                    sidecar_paths.push(sidecar_path);
+                    break;
+                }
+            }
+        }
+    }
+    sidecar_paths
+}
+
+/// Creates the directory along with any missing parents, failing if the path is not a directory
+pub fn create_output_dir(path: &Path) -> Result<()> {
+    if path.exists() {
+        anyhow::ensure!(path.is_dir(), "Output path {:?} must be a directory", path);
+        return Ok(());
+    }
+    fs::create_dir_all(path)?;
+    Ok(())
+}
+
+pub fn path_exists(path: PathBuf) -> bool {
+    fs::metadata(path).is_ok()
+}
+
+pub fn read_metadata(
+    filename: PathBuf,
+    prefer_sidecar: bool,
+    rating_tag: &str,
+    rating_source: RatingSource,
+    xmp_search: XmpSearch,
+) -> Result<FileMeta> {
+    if !path_exists(filename.clone()) {
+        return Err(MetadataError::FileNotFound.into());
+    }
+
+    let sidecar_xmp = match find_sidecar(&filename) {
+        Some(sidecar) => Some(read_sidecar(sidecar)?),
+        None => None,
+    };
+    let sidecar_rating = match &sidecar_xmp {
+        Some(sidecar_xmp) => sidecar_rating(sidecar_xmp, rating_tag)?,
+        None => None,
+    };
+    let sidecar_flag = sidecar_xmp.as_ref().and_then(flag_from_xmp);
+
+    // Use xmp-toolkit for video files
+    if is_video(&filename) {
+        let xmp_meta = read_xmp(filename.clone(), xmp_search).ok();
+        let embedded_rating = xmp_meta
+            .as_ref()
+            .and_then(|xmp_meta| rating_from_xmp(xmp_meta, rating_tag).ok().flatten())
+            .map(|rating| normalize_rating(rating_tag, rating));
+        let sidecar_rating = sidecar_rating.map(|rating| normalize_rating(rating_tag, rating));
+        let rating = match rating_source {
+            RatingSource::Embedded => {
+                let rating = match prefer_sidecar {
+                    true => sidecar_rating.or(embedded_rating),
+                    false => embedded_rating.or(sidecar_rating),
+                };
+                // Some cameras and DAMs only store the rating in QuickTime atoms
+                match rating {
+                    Some(rating) if rating != 0 => Some(rating),
+                    _ => read_rating_quicktime(&filename).ok().flatten().or(rating),
+                }
+            }
+            // QuickTime atoms take the place of the exiv2 tag, which videos don't have
+            rating_source => rating_source.select(
+                read_rating_quicktime(&filename).ok().flatten(),
+                embedded_rating,
+                sidecar_rating,
+            ),
+        };
+        return Ok(FileMeta {
+            rating,
+            label: xmp_meta.as_ref().and_then(label_from_xmp),
+            flag: rating_source.select_flag(
+                prefer_sidecar,
+                xmp_meta.as_ref().and_then(flag_from_xmp),
+                sidecar_flag,
+            ),
+            capture_date: xmp_meta.as_ref().and_then(create_date_from_xmp),
+            camera: None,
+            lens: None,
+            orientation: None,
+            has_gps: false,
+            iso: None,
+            fnumber: None,
+            exposure_time: None,
+        });
+    }
+
+    // Use rexiv2 for image files, falling back to the sidecar when the tag is absent
+    let meta = Metadata::new_from_path(&filename);
+    match meta {
+        Ok(meta) => {
+            let tag_rating = meta
+                .has_tag(rating_tag)
+                .then(|| meta.get_tag_numeric(rating_tag));
+            let rating = match rating_source {
+                RatingSource::Embedded => {
+                    // gexiv2 doesn't surface the embedded XMP of many HEIC and PNG files,
+                    // scan for the packet manually like for videos before settling on 0
+                    let embedded_rating = match tag_rating {
+                        Some(rating) if rating != 0 => Some(rating),
+                        tag_rating => read_rating_xmp(filename.clone(), rating_tag, xmp_search)
+                            .ok()
+                            .flatten()
+                            .or(tag_rating),
+                    };
+                    match prefer_sidecar {
+                        true => sidecar_rating.or(embedded_rating),
+                        false => embedded_rating.or(sidecar_rating),
+                    }
+                }
+                rating_source => rating_source.select(
+                    tag_rating,
+                    read_rating_xmp(filename.clone(), rating_tag, xmp_search)
+                        .ok()
+                        .flatten(),
+                    sidecar_rating,
+                ),
+            };
+            let capture_date = meta
+                .get_tag_string("Exif.Photo.DateTimeOriginal")
+                .ok()
+                .and_then(|date| parse_exif_date(&date));
+            let camera = ["Exif.Image.Make", "Exif.Image.Model"]
+                .iter()
+                .filter_map(|tag| meta.get_tag_string(tag).ok())
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+                .reduce(|make, model| format!("{make} {model}"));
+            let lens = LENS_TAGS
+                .iter()
+                .filter_map(|tag| meta.get_tag_string(tag).ok())
+                .map(|value| value.trim().to_string())
+                .find(|value| !value.is_empty());
+            Ok(FileMeta {
+                rating: rating.map(|rating| normalize_rating(rating_tag, rating)),
+                label: meta.get_tag_string("Xmp.xmp.Label").ok(),
+                flag: rating_source.select_flag(
+                    prefer_sidecar,
+                    meta.get_tag_string("Xmp.xmpDM.pick")
+                        .ok()
+                        .map(|pick| PickFlag::from_pick(&pick)),
+                    sidecar_flag,
+                ),
+                capture_date,
+                camera,
+                lens,
+                orientation: Some(meta.get_orientation() as i32),
+                has_gps: meta.get_gps_info().is_some(),
+                iso: meta
+                    .has_tag(ISO_TAG)
+                    .then(|| meta.get_tag_numeric(ISO_TAG))
+                    .filter(|iso| *iso > 0),
+                fnumber: rational_tag(&meta, FNUMBER_TAG),
+                exposure_time: rational_tag(&meta, EXPOSURE_TIME_TAG),
+            })
+        }
+        Err(e) => {
+            // None when there is no readable XMP packet either
+            let xmp_rating = read_rating_xmp(filename, rating_tag, xmp_search).ok();
+            let rating = match rating_source {
+                RatingSource::Embedded => match prefer_sidecar {
+                    true => sidecar_rating.or(xmp_rating.flatten()),
+                    false => xmp_rating.flatten().or(sidecar_rating),
+                },
+                rating_source => rating_source.select(None, xmp_rating.flatten(), sidecar_rating),
+            };
+            if rating.is_none() && xmp_rating.is_none() {
+                anyhow::bail!(e);
+            }
+            Ok(FileMeta {
+                rating: rating.map(|rating| normalize_rating(rating_tag, rating)),
+                label: None,
+                flag: rating_source.select_flag(prefer_sidecar, None, sidecar_flag),
+                capture_date: None,
+                camera: None,
+                lens: None,
+                orientation: None,
+                has_gps: false,
+                iso: None,
+                fnumber: None,
+                exposure_time: None,
+            })
+        }
+    }
+}
+
+/// Reads an unsigned rational tag such as `1/250` as a decimal, ignoring zero denominators
+fn rational_tag(meta: &Metadata, tag: &str) -> Option<f64> {
+    let ratio = meta.get_tag_rational(tag)?;
+    match *ratio.denom() {
+        0 => None,
+        denom => Some(*ratio.numer() as f64 / denom as f64).filter(|value| *value > 0.0),
+    }
+}
+
+/// Formats a rating for messages, files without a rating tag are `unrated`
+pub fn rating_text(rating: Option<i32>) -> String {
+    match rating {
+        Some(rating) => rating.to_string(),
+        None => "unrated".to_string(),
+    }
+}
+
+/// Converts the Microsoft 0-99 percentage scale into 0-5 stars, other tags are returned as is
+fn normalize_rating(rating_tag: &str, rating: i32) -> i32 {
+    match rating_tag {
+        MICROSOFT_RATING_TAG => stars_from_percent(rating),
+        _ => rating,
+    }
+}
+
+fn stars_from_percent(rating: i32) -> i32 {
+    match rating {
+        ..=0 => 0,
+        1..=12 => 1,
+        13..=37 => 2,
+        38..=62 => 3,
+        63..=87 => 4,
+        _ => 5,
+    }
+}
+
+fn denormalize_rating(rating_tag: &str, rating: i32) -> i32 {
+    match rating_tag {
+        MICROSOFT_RATING_TAG => percent_from_stars(rating),
+        _ => rating,
+    }
+}
+
+/// The percentages Windows writes for each star count
+fn percent_from_stars(rating: i32) -> i32 {
+    match rating {
+        ..=0 => 0,
+        1 => 1,
+        2 => 25,
+        3 => 50,
+        4 => 75,
+        _ => 99,
+    }
+}
+
+fn parse_exif_date(date: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(date.trim(), "%Y:%m:%d %H:%M:%S").ok()
+}
+
+/// Parses a point in time given as a date, a date and time, or a duration before now such as
+/// `30m`, `24h`, `7d` or `2w`
+pub fn parse_time_bound(value: &str) -> Result<NaiveDateTime, String> {
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(date.and_time(NaiveTime::MIN));
+    }
+    for format in ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M"] {
+        if let Ok(date) = NaiveDateTime::parse_from_str(value, format) {
+            return Ok(date);
+        }
+    }
+
+    let invalid = || format!("{value:?} is neither a date (YYYY-MM-DD) nor a duration like 7d");
+    let (amount, unit) = value.split_at(value.len().saturating_sub(1));
+    let amount: i64 = amount.parse().map_err(|_| invalid())?;
+    let duration = match unit {
+        "m" => TimeDelta::try_minutes(amount),
+        "h" => TimeDelta::try_hours(amount),
+        "d" => TimeDelta::try_days(amount),
+        "w" => TimeDelta::try_weeks(amount),
+        _ => None,
+    }
+    .ok_or_else(invalid)?;
+    Ok(Local::now().naive_local() - duration)
+}
+
+/// Parses an exposure time given in seconds, either as a fraction like `1/250` or a decimal
+pub fn parse_exposure_time(value: &str) -> Result<f64, String> {
+    let invalid = || format!("{value:?} is not an exposure time like 1/250 or 0.5");
+    let seconds = match value.split_once('/') {
+        Some((numer, denom)) => {
+            let numer: f64 = numer.trim().parse().map_err(|_| invalid())?;
+            let denom: f64 = denom.trim().parse().map_err(|_| invalid())?;
+            numer / denom
+        }
+        None => value.trim().parse().map_err(|_| invalid())?,
+    };
+    match seconds.is_finite() && seconds > 0.0 {
+        true => Ok(seconds),
+        false => Err(invalid()),
+    }
+}
+
+/// Ratings written for color labels, parsed from a list like `Red=5,Yellow=3`
+#[derive(Clone, PartialEq, Debug)]
+pub struct LabelRatings(Vec<(String, i32)>);
+
+impl LabelRatings {
+    pub fn parse(value: &str) -> Result<LabelRatings, String> {
+        let mut label_ratings: Vec<(String, i32)> = Vec::new();
+        for pair in value.split(',') {
+            let invalid = || format!("{pair:?} is not a label and rating like Red=5");
+            let (label, rating) = pair.split_once('=').ok_or_else(invalid)?;
+            let (label, rating) = (label.trim(), rating.trim());
+            let rating: i32 = rating.parse().map_err(|_| invalid())?;
+            if label.is_empty() || !(0..=5).contains(&rating) {
+                return Err(invalid());
+            }
+            if label_ratings.iter().any(|(existing, _)| existing == label) {
+                return Err(format!("Label {label:?} is mapped more than once"));
+            }
+            label_ratings.push((label.to_string(), rating));
+        }
+        Ok(LabelRatings(label_ratings))
+    }
+
+    /// Rating mapped to the label, `None` when the label isn't mapped
+    pub fn rating_for(&self, label: Option<&str>) -> Option<i32> {
+        let label = label?;
+        self.0
+            .iter()
+            .find(|(mapped, _)| mapped == label)
+            .map(|(_, rating)| *rating)
+    }
+}
+
+pub fn get_modified_date(filename: &Path) -> Result<NaiveDateTime> {
+    let modified = fs::metadata(filename)?.modified()?;
+    Ok(DateTime::<Local>::from(modified).naive_local())
+}
+
+pub fn get_rating(
+    filename: PathBuf,
+    prefer_sidecar: bool,
+    rating_tag: &str,
+    rating_source: RatingSource,
+    xmp_search: XmpSearch,
+) -> Result<Option<i32>> {
+    Ok(read_metadata(
+        filename,
+        prefer_sidecar,
+        rating_tag,
+        rating_source,
+        xmp_search,
+    )?
+    .rating)
+}
+
+/// Embedded rating of a file that disagrees with the rating of its .xmp sidecar
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+pub struct RatingConflict {
+    pub embedded: i32,
+    pub sidecar: i32,
+}
+
+/// Compares the embedded rating with the one of the .xmp sidecar, files missing either
+/// rating have no conflict
+pub fn find_rating_conflict(
+    filename: PathBuf,
+    rating_tag: &str,
+    xmp_search: XmpSearch,
+) -> Result<Option<RatingConflict>> {
+    if find_sidecar(&filename).is_none() {
+        return Ok(None);
+    }
+    let read_rating = |rating_source| {
+        get_rating(
+            filename.clone(),
+            false,
+            rating_tag,
+            rating_source,
+            xmp_search,
+        )
+    };
+    // Without an embedded rating the sidecar one is picked up, which never conflicts
+    let embedded = read_rating(RatingSource::Embedded)?;
+    let sidecar = read_rating(RatingSource::Sidecar)?;
+    Ok(match (embedded, sidecar) {
+        (Some(embedded), Some(sidecar)) if embedded != sidecar => {
+            Some(RatingConflict { embedded, sidecar })
+        }
+        _ => None,
+    })
+}
+
+pub fn get_label(filename: PathBuf) -> Result<Option<String>> {
+    Ok(read_metadata(
+        filename,
+        false,
+        DEFAULT_RATING_TAG,
+        RatingSource::Embedded,
+        XmpSearch::default(),
+    )?
+    .label)
+}
+
+/// Writes a star rating to `rating_tag`, stored as a percentage for `Xmp.MicrosoftPhoto.Rating`
+pub fn write_rating(filename: PathBuf, rating_tag: &str, rating: i32) -> Result<()> {
+    let rating = denormalize_rating(rating_tag, rating);
+    // Use xmp-toolkit for video files
+    if is_video(&filename) {
+        return write_rating_xmp(filename, rating_tag, rating);
+    }
+
+    // Use rexiv2 for image files
+    let meta = Metadata::new_from_path(&filename)?;
+    meta.set_tag_numeric(rating_tag, rating)?;
+    meta.save_to_file(&filename)?;
+    Ok(())
+}
+
+/// Writes `Xmp.xmp.Label`, an empty label removes the tag
+pub fn write_label(filename: PathBuf, label: &str) -> Result<()> {
+    if is_video(&filename) {
+        return write_label_xmp(filename, label);
+    }
+
+    let meta = Metadata::new_from_path(&filename)?;
+    match label {
+        "" => {
+            meta.clear_tag("Xmp.xmp.Label");
+        }
+        label => meta.set_tag_string("Xmp.xmp.Label", label)?,
+    }
+    meta.save_to_file(&filename)?;
+    Ok(())
+}
+
+/// Whether exiv2 can write the format, which [`clear_metadata`] needs
+pub fn supports_clearing_metadata(filename: &Path) -> bool {
+    filename
+        .extension()
+        .and_then(OsStr::to_str)
+        .is_some_and(|extension| {
+            CLEARABLE_METADATA_EXTENSIONS.contains(&extension.to_lowercase().as_str())
+        })
+}
+
+/// Removes all EXIF, IPTC and XMP tags from the file
+pub fn clear_metadata(filename: &Path) -> Result<()> {
+    let meta = Metadata::new_from_path(filename)?;
+    meta.clear();
+    meta.save_to_file(filename)?;
+    Ok(())
+}
+
+pub fn delete_gps(filename: PathBuf) -> Result<()> {
+    if is_video(&filename) {
+        anyhow::bail!("Removing GPS data from videos is not supported");
+    }
+
+    let meta = Metadata::new_from_path(&filename)?;
+    meta.delete_gps_info();
+    meta.save_to_file(&filename)?;
+    Ok(())
+}
+
+pub fn set_rating(path: PathBuf, rating: i32) -> Result<(), ExifToolError> {
+    let mut exiftool = ExifTool::new().unwrap();
+    exiftool.write_tag(path.as_path(), "Rating", &rating, &["-overwrite_original"])
+}
+
+pub fn hash_file(path: &Path) -> Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(fs::File::open(path)?)?;
+    Ok(hasher.finalize())
+}
+
+pub fn is_video(path: &Path) -> bool {
+    classify(path) == MediaKind::Video
+}
+
+pub fn get_tags(filename: PathBuf) -> Result<Option<Vec<String>>> {
+    if !path_exists(filename.clone()) {
+        return Err(MetadataError::FileNotFound.into());
+    }
+
+    let meta = Metadata::new_from_path(filename);
+    match meta {
+        Ok(meta) => {
+            let tags = meta.get_tag_multiple_strings("Xmp.digiKam.TagsList");
+            match tags {
+                Ok(tags) => Ok(Some(tags.iter().map(|t| t.to_lowercase()).collect())),
+                Err(_) => Ok(None),
+            }
+        }
+        Err(e) => anyhow::bail!(e),
+    }
+}
+
+fn is_file_allowed(
+    filename: &Path,
+    include_videos: bool,
+    include_raw_only: bool,
+    extension_filter: &ExtensionFilter,
+) -> bool {
+    let extension = filename
+        .extension()
+        .and_then(OsStr::to_str)
+        .map(str::to_lowercase)
+        .unwrap_or_default();
+    if extension_filter.excludes(&extension) {
+        return false;
+    }
+    match classify(filename) {
+        MediaKind::Image => extension_filter.allows_image(&extension),
+        MediaKind::Video => include_videos,
+        MediaKind::Raw => include_raw_only,
+        MediaKind::Other => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(src: PathBuf, max_depth: Option<i32>) -> Config {
+        Config {
+            src,
+            raw_src: None,
+            flat_raw_src: false,
+            exclude: Vec::new(),
+            flip_exclusion: false,
+            exclude_recursive: false,
+            include_videos: false,
+            include_raw_only: false,
+            orphan_raws: false,
+            match_raws: false,
+            raw_stem: RawStem::Full,
+            max_depth,
+            max_scan: None,
+            follow_symlinks: false,
+            include_glob: Vec::new(),
+            exclude_glob: Vec::new(),
+            no_ignore: false,
+            skip_dir: None,
+            group_bursts: false,
+            extensions: Vec::new(),
+            exclude_extensions: Vec::new(),
+        }
+    }
+
+    fn filter(threshold: i32) -> Filter {
+        Filter {
+            threshold,
+            comparison_command: ComparisonCommand::MoreEqual,
+            min_rating: None,
+            max_rating: None,
+            unrated: false,
+            treat_missing_as: MissingRating::Zero,
+            label: Vec::new(),
+            label_match: LabelMatch::Any,
+            flag: None,
+            include_tag: None,
+            ignore_tag: None,
+            after: None,
+            before: None,
+            include_undated: false,
+            newer_than: None,
+            older_than: None,
+            camera: None,
+            lens: None,
+            fix_orientation: false,
+            has_gps: None,
+            min_iso: None,
+            max_iso: None,
+            min_fnumber: None,
+            max_fnumber: None,
+            min_exposure_time: None,
+            max_exposure_time: None,
+            include_missing_exposure: false,
+            inverse: false,
+            prefer_sidecar: false,
+            rating_source: RatingSource::Embedded,
+            rating_from: RatingFrom::default(),
+            rating_tag: DEFAULT_RATING_TAG.to_string(),
+            xmp_search: XmpSearch::default(),
+        }
+    }
+
+    #[test]
+    fn classify_ignores_extension_case() {
+        assert_eq!(classify(Path::new("IMG.JPG")), MediaKind::Image);
+        assert_eq!(classify(Path::new("dir/clip.Mp4")), MediaKind::Video);
+        assert_eq!(classify(Path::new("DSC.ARW")), MediaKind::Raw);
+        assert_eq!(classify(Path::new("notes.txt")), MediaKind::Other);
+    }
+
+    #[test]
+    fn parse_image_extension_rejects_other_kinds() {
+        assert_eq!(parse_image_extension(".JPG"), Ok("jpg".to_string()));
+        assert_eq!(parse_image_extension("heic"), Ok("heic".to_string()));
+        for extension in ["gif", "cr2", "mov", "", "tar.gz"] {
+            assert!(parse_image_extension(extension).is_err(), "{extension}");
+        }
+    }
+
+    #[test]
+    fn classify_without_extension() {
+        assert_eq!(classify(Path::new("IMG")), MediaKind::Other);
+        assert_eq!(classify(Path::new("dir.jpg/IMG")), MediaKind::Other);
+    }
+
+    #[test]
+    fn classify_dotfiles() {
+        assert_eq!(classify(Path::new(".jpg")), MediaKind::Other);
+        assert_eq!(classify(Path::new("._IMG.jpg")), MediaKind::Other);
+        assert_eq!(classify(Path::new("dir/.hidden.mov")), MediaKind::Other);
+        let extension_filter = ExtensionFilter {
+            images: Vec::new(),
+            excluded: Vec::new(),
+        };
+        assert!(!is_file_allowed(
+            Path::new("._IMG.jpg"),
+            true,
+            true,
+            &extension_filter
+        ));
+    }
+
+    #[test]
+    fn rating_round_trips_for_tiff_and_webp() {
+        rexiv2::initialize().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let samples: [(&str, &[u8]); 2] = [
+            ("sample.tif", include_bytes!("../tests/fixtures/sample.tif")),
+            (
+                "sample.webp",
+                include_bytes!("../tests/fixtures/sample.webp"),
+            ),
+        ];
+        for (file_name, data) in samples {
+            let path = dir.path().join(file_name);
+            fs::write(&path, data).unwrap();
+            assert_eq!(classify(&path), MediaKind::Image);
+
+            write_rating(path.clone(), DEFAULT_RATING_TAG, 4).unwrap();
+            assert_eq!(
+                get_rating(
+                    path,
+                    false,
+                    DEFAULT_RATING_TAG,
+                    RatingSource::Embedded,
+                    XmpSearch::default()
+                )
+                .unwrap(),
+                Some(4),
+                "{file_name}"
+            );
+        }
+    }
+
+    #[test]
+    fn unreadable_image_uses_sidecar_rating() {
+        rexiv2::initialize().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("broken.jpg");
+        fs::write(&path, b"not an image").unwrap();
+        fs::write(
+            dir.path().join("broken.xmp"),
+            "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\
+            <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\
+            <rdf:Description xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\" xmp:Rating=\"4\"/>\
+            </rdf:RDF></x:xmpmeta>",
+        )
+        .unwrap();
+
+        assert_eq!(
+            get_rating(
+                path,
+                false,
+                DEFAULT_RATING_TAG,
+                RatingSource::Embedded,
+                XmpSearch::default()
+            )
+            .unwrap(),
+            Some(4)
+        );
+    }
+
+    #[test]
+    fn microsoft_rating_round_trips() {
+        rexiv2::initialize().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sample.tif");
+        fs::write(&path, include_bytes!("../tests/fixtures/sample.tif")).unwrap();
+
+        write_rating(path.clone(), MICROSOFT_RATING_TAG, 4).unwrap();
+        let read = |rating_tag| {
+            get_rating(
+                path.clone(),
+                false,
+                rating_tag,
+                RatingSource::Embedded,
+                XmpSearch::default(),
+            )
+            .unwrap()
+        };
+        assert_eq!(read(MICROSOFT_RATING_TAG), Some(4));
+        assert_eq!(read(DEFAULT_RATING_TAG), None);
+    }
+
+    #[test]
+    fn microsoft_percentages_map_back_to_stars() {
+        for stars in 0..=5 {
+            let percent = denormalize_rating(MICROSOFT_RATING_TAG, stars);
+            assert_eq!(normalize_rating(MICROSOFT_RATING_TAG, percent), stars);
+        }
+        assert_eq!(denormalize_rating(MICROSOFT_RATING_TAG, 5), 99);
+        assert_eq!(denormalize_rating(DEFAULT_RATING_TAG, 5), 5);
+    }
+
+    #[test]
+    fn embedded_rating_conflicts_with_sidecar() {
+        rexiv2::initialize().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sample.tif");
+        fs::write(&path, include_bytes!("../tests/fixtures/sample.tif")).unwrap();
+        write_rating(path.clone(), DEFAULT_RATING_TAG, 3).unwrap();
+        let conflict = || {
+            find_rating_conflict(path.clone(), DEFAULT_RATING_TAG, XmpSearch::default()).unwrap()
+        };
+        let write_sidecar = |rating: i32| {
+            let sidecar = format!(
+                "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\
+                <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\
+                <rdf:Description xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\" xmp:Rating=\"{rating}\"/>\
+                </rdf:RDF></x:xmpmeta>"
+            );
+            fs::write(dir.path().join("sample.xmp"), sidecar).unwrap();
+        };
+
+        assert_eq!(conflict(), None);
+        write_sidecar(3);
+        assert_eq!(conflict(), None);
+        write_sidecar(5);
+        assert_eq!(
+            conflict(),
+            Some(RatingConflict {
+                embedded: 3,
+                sidecar: 5
+            })
+        );
+    }
+
+    #[test]
+    fn embedded_rating_of_avif_and_jxl() {
+        rexiv2::initialize().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let samples: [(&str, &[u8]); 2] = [
+            (
+                "sample.avif",
+                include_bytes!("../tests/fixtures/sample.avif"),
+            ),
+            ("sample.JXL", include_bytes!("../tests/fixtures/sample.jxl")),
+        ];
+        for (file_name, data) in samples {
+            let path = dir.path().join(file_name);
+            fs::write(&path, data).unwrap();
+            assert_eq!(classify(&path), MediaKind::Image);
+            // Falls back to the XMP packet when exiv2 can't read the file
+            assert_eq!(
+                get_rating(
+                    path,
+                    false,
+                    DEFAULT_RATING_TAG,
+                    RatingSource::Embedded,
+                    XmpSearch::default()
+                )
+                .unwrap(),
+                Some(4),
+                "{file_name}"
+            );
+        }
+    }
+
+    #[test]
+    fn collect_entries_respects_max_depth() {
+        let src = tempfile::tempdir().unwrap();
+        let nested = src.path().join("a").join("b");
+        fs::create_dir_all(&nested).unwrap();
+        for dir in [src.path(), &src.path().join("a"), &nested] {
+            fs::write(dir.join("IMG.jpg"), []).unwrap();
+        }
+
+        let collected = |max_depth| {
+            let mut paths: Vec<PathBuf> = collect_entries(&config(src.path().into(), max_depth))
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.path)
+                .collect();
+            paths.sort();
+            paths
+        };
+
+        assert_eq!(collected(Some(0)), vec![src.path().join("IMG.jpg")]);
+        assert_eq!(
+            collected(Some(1)),
+            vec![src.path().join("IMG.jpg"), src.path().join("a/IMG.jpg")]
+        );
+        assert_eq!(collected(None).len(), 3);
+    }
+
+    #[test]
+    fn collect_entries_skips_nested_destination() {
+        let src = tempfile::tempdir().unwrap();
+        let dest = src.path().join("Selects");
+        fs::create_dir(&dest).unwrap();
+        fs::write(src.path().join("IMG.jpg"), []).unwrap();
+        fs::write(dest.join("IMG_moved.jpg"), []).unwrap();
+
+        let mut config = config(src.path().into(), None);
+        // Skipped by its canonical path, however it is spelled
+        config.skip_dir = Some(src.path().join(".").join("Selects"));
+        let entries = collect_entries(&config).unwrap();
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path, src.path().join("IMG.jpg"));
+    }
+
+    #[test]
+    fn create_output_dir_creates_missing_parents() {
+        let root = tempfile::tempdir().unwrap();
+        let dest = root.path().join("a").join("b").join("c");
+
+        create_output_dir(&dest).unwrap();
+        assert!(dest.is_dir());
+        // Existing directories are reused, files are rejected
+        create_output_dir(&dest).unwrap();
+        let file = dest.join("IMG.jpg");
+        fs::write(&file, []).unwrap();
+        assert!(create_output_dir(&file).is_err());
+    }
+
+    #[test]
+    fn burst_key_skips_date_time_names() {
+        let key = |name: &str| burst_key(Path::new(name)).map(|key| key.display().to_string());
+        assert_eq!(key("IMG_1234_001.jpg"), Some("IMG_1234".to_string()));
+        assert_eq!(key("DSC01234-2.jpg"), Some("DSC01234".to_string()));
+        assert_eq!(key("IMG_1234.jpg"), None);
+        assert_eq!(key("IMG_1234_0001.jpg"), None);
+        assert_eq!(key("IMG_20240703_120000.jpg"), None);
+        assert_eq!(key("PXL_20240703_120000123.jpg"), None);
+        assert_eq!(key("20240703_001.jpg"), None);
+    }
+
+    #[test]
+    fn pick_flag_from_xmp_dm_pick() {
+        assert_eq!(PickFlag::from_pick("1"), PickFlag::Pick);
+        assert_eq!(PickFlag::from_pick(" -1 "), PickFlag::Reject);
+        assert_eq!(PickFlag::from_pick("0"), PickFlag::Unflagged);
+        assert_eq!(PickFlag::from_pick("yes"), PickFlag::Unflagged);
+    }
+
+    #[test]
+    fn pick_flag_follows_rating_source() {
+        let (pick, reject) = (Some(PickFlag::Pick), Some(PickFlag::Reject));
+        assert_eq!(
+            RatingSource::Embedded.select_flag(false, pick, reject),
+            PickFlag::Pick
+        );
+        assert_eq!(
+            RatingSource::Embedded.select_flag(true, pick, reject),
+            PickFlag::Reject
+        );
+        assert_eq!(
+            RatingSource::Max.select_flag(false, None, reject),
+            PickFlag::Reject
+        );
+        assert_eq!(
+            RatingSource::Sidecar.select_flag(false, pick, None),
+            PickFlag::Unflagged
+        );
+        assert_eq!(
+            RatingSource::Xmp.select_flag(true, pick, reject),
+            PickFlag::Pick
+        );
+    }
+
+    #[test]
+    fn evaluate_meta_matches_pick_flag() {
+        let entry = Entry::new(PathBuf::from("a.jpg"));
+        let meta = |flag| FileMeta {
+            rating: Some(5),
+            flag,
+            ..FileMeta::default()
+        };
+        let picks = Filter {
+            flag: Some(PickFlag::Pick),
+            ..filter(5)
+        };
+        assert!(evaluate_meta(&entry, &picks, meta(PickFlag::Pick))
+            .unwrap()
+            .is_some());
+        assert!(evaluate_meta(&entry, &picks, meta(PickFlag::Reject))
+            .unwrap()
+            .is_none());
+        assert!(evaluate_meta(&entry, &filter(5), meta(PickFlag::Reject))
+            .unwrap()
+            .is_some());
+
+        let unflagged = Filter {
+            flag: Some(PickFlag::Unflagged),
+            inverse: true,
+            ..filter(5)
+        };
+        assert!(evaluate_meta(&entry, &unflagged, meta(PickFlag::Unflagged))
+            .unwrap()
+            .is_none());
+        assert!(evaluate_meta(&entry, &unflagged, meta(PickFlag::Pick))
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn label_ratings_map_labels() {
+        let label_ratings = LabelRatings::parse("Red=5, Yellow = 3").unwrap();
+        assert_eq!(label_ratings.rating_for(Some("Red")), Some(5));
+        assert_eq!(label_ratings.rating_for(Some("Yellow")), Some(3));
+        assert_eq!(label_ratings.rating_for(Some("Green")), None);
+        assert_eq!(label_ratings.rating_for(None), None);
+
+        for value in ["", "Red", "Red=6", "=3", "Red=5,Red=4"] {
+            assert!(LabelRatings::parse(value).is_err(), "{value}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn collect_entries_handles_non_utf8_names() {
+        use std::ffi::OsString;
+        use std::os::unix::ffi::OsStringExt;
+
+        let src = tempfile::tempdir().unwrap();
+        let dir = src.path().join(OsString::from_vec(b"caf\xe9".to_vec()));
+        if fs::create_dir(&dir).is_err() {
+            // Some filesystems only accept UTF-8 names
+            return;
+        }
+        let image = dir.join(OsString::from_vec(b"IMG_\xff.jpg".to_vec()));
+        fs::write(&image, []).unwrap();
+        fs::write(dir.join(OsString::from_vec(b"IMG_\xff.CR2".to_vec())), []).unwrap();
+
+        let mut config = config(src.path().into(), None);
+        config.match_raws = true;
+        let entries = collect_entries(&config).unwrap();
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path, image);
+        assert_eq!(entries[0].raw_paths.len(), 1);
+    }
+}
@@ -1,59 +1,166 @@
-#![feature(trim_prefix_suffix)]
-
-use crate::xmp::read_rating_xmp;
-use anyhow::{anyhow, Result};
-use clap::{Parser, Subcommand, ValueEnum};
-use exiftool::{ExifTool, ExifToolError};
-use rexiv2::Metadata;
-use std::ffi::OsStr;
+use anyhow::{anyhow, ensure, Context, Result};
+use chrono::{NaiveDate, NaiveDateTime};
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, error, info, warn, Level, LevelFilter};
+use rayon::prelude::*;
+use rayon::ThreadPool;
+use rust_exif::cache::MetadataCache;
+use rust_exif::commands::{
+    apply_command, apply_plan, shell_quote, ChecksumMismatch, CollisionMode, CommandContext,
+    CommandTargets, DestinationDirs, FileCommand, Outcome,
+};
+use rust_exif::journal::{journaled_sources, undo_journal, Journal};
+use rust_exif::manifest::Manifest;
+use rust_exif::rename::NameTemplate;
+use rust_exif::route::Routes;
+use rust_exif::{
+    create_output_dir, entries_from_paths, evaluate_cached, evaluate_with_meta,
+    find_rating_conflict, get_modified_date, hash_file, parse_exposure_time, parse_extension,
+    parse_image_extension, parse_time_bound, rating_text, read_metadata, traverse,
+    ComparisonCommand, Config, Entry, ErrorCategory, FileMeta, Filter, LabelMatch, LabelRatings,
+    MissingRating, PickFlag, RatingConflict, RatingFrom, RatingSource, RawStem, XmpSearch,
+    DEFAULT_RATING_TAG, DEFAULT_XMP_MAX_BYTES, DEFAULT_XMP_TAIL_BYTES,
+    DEFAULT_XMP_WHOLE_FILE_BYTES,
+};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
 use std::fmt::{Display, Formatter};
+use std::io::{IsTerminal, Write};
 use std::path::{Path, PathBuf};
-use std::{fmt, fs, io};
-
-mod xmp;
-
-const IMAGE_EXTENSIONS: [&str; 4] = ["heic", "jpg", "jpeg", "png"];
-// TODO: restore multiple RAW file extension support
-// const RAW_IMAGE_EXENSIONS: [&str; 2] = ["arw", "dng"];
-const VIDEOS_EXTENSIONS: [&str; 3] = ["mov", "mp4", "avi"];
+use std::process::ExitCode;
+use std::sync::Mutex;
+use std::{env, fmt, fs, io, iter};
 
 #[derive(Parser)]
 struct Cli {
     #[command(subcommand)]
     command: FileCommand,
 
-    #[arg(short = 't', long, default_value_t = 5)]
+    /// Rating compared against by --comparison-command, -1 is the rating of rejected files
+    #[arg(
+        short = 't',
+        long,
+        default_value_t = 5,
+        value_parser = clap::value_parser!(i32).range(-1..=5)
+    )]
     threshold: i32,
 
     #[arg(short = 'i', long, default_value_t = false)]
     inverse: bool,
 
-    #[arg(short = 'v', long, default_value_t = false)]
-    verbose: bool,
+    /// Log each file operation, repeat (-vv) to also log skipped and matched files.
+    /// RUST_LOG takes precedence
+    #[arg(short = 'v', long, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Only report errors on stderr, without warnings, progress or the summary.
+    /// Dry-run and print output on stdout is kept
+    #[arg(short = 'q', long, default_value_t = false, conflicts_with = "verbose")]
+    quiet: bool,
 
     #[arg(short = 'd', long)]
     dest: Option<std::path::PathBuf>,
 
+    /// Directory to select files from, required by every command but undo, apply-plan,
+    /// completions and manpage
     #[arg(short = 's', long)]
-    src: std::path::PathBuf,
+    src: Option<std::path::PathBuf>,
 
-    #[arg(long)]
+    /// Match raws in this directory, e.g. `RAW/` next to `JPEG/`, instead of next to
+    /// their images. Subdirectories of the source are mirrored in it
+    #[arg(long, visible_alias = "raw-dir")]
     raw_src: Option<std::path::PathBuf>,
 
+    /// Look for raws directly in --raw-src instead of mirroring the source subdirectories
+    #[arg(long, default_value_t = false, requires = "raw_src")]
+    flat_raw_src: bool,
+
+    /// Skip directories whose name contains this text, or matches this regular
+    /// expression when prefixed with `re:`, e.g. `re:^raw$`
     #[arg(short = 'e', long)]
     exclude: Vec<String>,
 
     #[arg(short = 'f', long, default_value_t = false)]
     flip_exclusion: bool,
 
+    /// Apply --exclude to directories at every depth, not only directly inside the source.
+    /// Has no effect together with --flip-exclusion
+    #[arg(long, default_value_t = false)]
+    exclude_recursive: bool,
+
     #[arg(short = 'm', long, default_value_t = false)]
     match_raws: bool,
 
+    /// Part of the image name raws are matched by, `base` lets `IMG_1.edit.jpg`
+    /// match `IMG_1.CR2`
+    #[arg(long, default_value_t = RawStem::Full)]
+    raw_stem: RawStem,
+
     #[arg(short = 'a', long, default_value_t = false)]
     include_videos: bool,
 
+    /// Select raw files and filter them by their own rating. With --match-raws only raws
+    /// without a matching image are selected, for shoots without JPEGs
+    #[arg(long, default_value_t = false)]
+    include_raw_only: bool,
+
+    /// Only select raw files without an image next to them, e.g. raws whose JPEG was
+    /// already deleted. Images left out by --ext or the glob options still count
+    #[arg(long, default_value_t = false, conflicts_with_all = ["include_raw_only", "raw_src"])]
+    orphan_raws: bool,
+
+    /// Do not descend more than N directory levels below the source, 0 only
+    /// processes files directly inside it
+    #[arg(long, value_parser = clap::value_parser!(i32).range(0..))]
+    max_depth: Option<i32>,
+
+    /// Abort before acting when the source holds more than N files, a guard against a
+    /// mistyped --src pointing too high in the tree
+    #[arg(long)]
+    max_scan: Option<usize>,
+
+    /// Descend into symlinked directories, each directory is still visited only once
+    #[arg(long, default_value_t = false)]
+    follow_symlinks: bool,
+
+    /// Only select files whose name matches one of these glob patterns, e.g. `DSC*`
+    #[arg(long)]
+    include_glob: Vec<String>,
+
+    /// Skip files whose name matches one of these glob patterns, even when
+    /// they also match --include-glob
+    #[arg(long)]
+    exclude_glob: Vec<String>,
+
+    /// Only select images with these extensions instead of all supported ones,
+    /// e.g. `jpg,heic`
+    #[arg(long, value_parser = parse_image_extension, value_delimiter = ',')]
+    ext: Vec<String>,
+
+    /// Skip files with these extensions, including videos and raws, e.g. `png,mov`
+    #[arg(long, value_parser = parse_extension, value_delimiter = ',')]
+    exclude_ext: Vec<String>,
+
+    /// Don't skip the paths listed in gitignore-style `.exifignore` files
+    #[arg(long, default_value_t = false)]
+    no_ignore: bool,
+
     #[arg(short = 'l', long)]
-    label: Option<String>,
+    label: Vec<String>,
+
+    /// How multiple --label values are combined. A file has a single label,
+    /// so `all` with more than one distinct label matches nothing
+    #[arg(long, default_value_t = LabelMatch::Any)]
+    label_match: LabelMatch,
+
+    /// Only select files with this Lightroom pick flag, read from `xmpDM:pick` of the file
+    /// or its sidecar like the rating. Files without the flag are unflagged
+    #[arg(long)]
+    flag: Option<PickFlag>,
 
     #[arg(long)]
     ignore_tag: Option<String>,
@@ -64,77 +171,980 @@ struct Cli {
     #[arg(short = 'n', long, default_value_t = false)]
     dry_run: bool,
 
+    /// Ask before each file is moved or deleted
+    #[arg(long, default_value_t = false, conflicts_with_all = ["dry_run", "from_stdin"])]
+    interactive: bool,
+
+    /// Shorthand for --on-collision overwrite
     #[arg(short = 'o', long, default_value_t = false)]
     r#override: bool,
 
+    #[arg(long, default_value_t = CollisionMode::Skip)]
+    on_collision: CollisionMode,
+
+    /// Give copies the access and modification times of their originals
+    #[arg(long, default_value_t = false)]
+    preserve_times: bool,
+
+    /// Compare the content hashes of copies and their originals, removing corrupted
+    /// copies. Moves to another drive are verified before the original is removed
+    #[arg(long, default_value_t = false)]
+    verify: bool,
+
+    /// Remove all EXIF, IPTC and XMP metadata from copied or moved images, only JPEG, PNG,
+    /// TIFF and WebP files are supported. Sources of copies keep theirs, moved files lose it
+    /// for good. Requires the copy or move command.
+    /// Can't be combined with --copy-sidecars, the sidecars hold the same metadata
+    #[arg(long, default_value_t = false, conflicts_with = "copy_sidecars")]
+    strip_metadata: bool,
+
     #[arg(short = 'c', long, default_value_t = ComparisonCommand::MoreEqual)]
     comparison_command: ComparisonCommand,
+
+    /// Lower bound of the accepted rating range, supersedes --threshold.
+    /// Combined with --inverse, files outside of the range are selected
+    #[arg(long)]
+    min_rating: Option<i32>,
+
+    /// Upper bound of the accepted rating range, supersedes --threshold.
+    /// Combined with --inverse, files outside of the range are selected
+    #[arg(long)]
+    max_rating: Option<i32>,
+
+    /// Only select files without any rating tag, instead of comparing the rating.
+    /// Files rated 0 have a rating and aren't selected
+    #[arg(long, default_value_t = false, conflicts_with_all = ["min_rating", "max_rating"])]
+    unrated: bool,
+
+    /// Only select files whose embedded rating disagrees with the rating of their .xmp
+    /// sidecar, printing both. Requires the print command
+    #[arg(long, default_value_t = false)]
+    find_conflicts: bool,
+
+    /// How files without a rating tag are compared against --threshold, the rating range
+    /// and --route conditions
+    #[arg(long, default_value_t = MissingRating::Zero)]
+    treat_missing_as: MissingRating,
+
+    #[arg(long, value_parser = clap::value_parser!(i32).range(0..=5))]
+    set_rating: Option<i32>,
+
+    /// Make `set-rating` write the rating mapped to the label of each file, e.g.
+    /// `Red=5,Yellow=3`. Files with other labels are skipped
+    #[arg(long, value_parser = LabelRatings::parse, conflicts_with = "set_rating")]
+    label_to_rating: Option<LabelRatings>,
+
+    /// Label written by `set-label`, e.g. `Red`. An empty value removes the label
+    #[arg(long)]
+    set_label: Option<String>,
+
+    /// New file name of `rename` without the extension, e.g. `{date:%Y%m%d}_{model}_{orig}`.
+    /// Fields are {date[:strftime format]}, {model}, {rating}, {label}, {seq[:width]} and {orig}
+    #[arg(long, value_parser = NameTemplate::parse)]
+    name_template: Option<NameTemplate>,
+
+    #[arg(short = 'j', long)]
+    jobs: Option<usize>,
+
+    #[arg(long, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+
+    /// Print the capture date after each path, `-` for files without one. The JSON
+    /// output always includes it
+    #[arg(long, default_value_t = false)]
+    show_date: bool,
+
+    /// Print paths with forward slashes on Windows as well, for scripts shared across
+    /// platforms
+    #[arg(long, default_value_t = false)]
+    posix_paths: bool,
+
+    /// Order in which the selected files are printed and processed
+    #[arg(long)]
+    sort: Option<SortKey>,
+
+    /// Reverse the --sort order
+    #[arg(long, default_value_t = false, requires = "sort")]
+    reverse: bool,
+
+    /// Only act on the first N selected files, in --sort order when given
+    #[arg(long)]
+    limit: Option<usize>,
+
+    /// Keep the N highest rated files of each directory and act on the rest, instead of
+    /// comparing ratings against --threshold. Equal ratings are ranked by file name
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "threshold",
+            "comparison_command",
+            "min_rating",
+            "max_rating",
+            "unrated",
+            "inverse",
+        ]
+    )]
+    top_n_per_dir: Option<usize>,
+
+    /// Leave out the first N selected files, applied before --limit to page through
+    /// them. Without --sort files come in path order, so pages shift whenever files
+    /// are added or removed between runs
+    #[arg(long)]
+    skip: Option<usize>,
+
+    #[arg(long, default_value_t = false)]
+    prefer_sidecar: bool,
+
+    /// Where to read ratings from when the exiv2 tag, the embedded XMP packet
+    /// and the sidecar disagree. --prefer-sidecar only applies to `embedded`
+    #[arg(long, default_value_t = RatingSource::Embedded)]
+    rating_source: RatingSource,
+
+    /// Read the rating from the matched raw files instead of the primary file, which
+    /// is only used when no raw is rated. Requires --match-raws
+    #[arg(long, default_value_t = RatingFrom::Primary, requires = "match_raws")]
+    rating_from: RatingFrom,
+
+    /// Tag to read the rating from, e.g. `Exif.Image.Rating`. Values of
+    /// `Xmp.MicrosoftPhoto.Rating` are converted from percentages to 0-5 stars
+    #[arg(long, default_value = DEFAULT_RATING_TAG)]
+    rating_tag: String,
+
+    /// Bytes at the end of a file searched for an embedded XMP packet, raise it for videos
+    /// storing the packet further from the end
+    #[arg(long, default_value_t = DEFAULT_XMP_TAIL_BYTES)]
+    xmp_tail_bytes: usize,
+
+    /// Bytes from the start of a file searched for an embedded XMP packet when the tail
+    /// has none
+    #[arg(long, default_value_t = DEFAULT_XMP_MAX_BYTES)]
+    xmp_max_bytes: usize,
+
+    /// Files up to this many bytes are searched whole for an XMP packet when neither their
+    /// end nor their start has one, 0 disables the whole file search
+    #[arg(long, default_value_t = DEFAULT_XMP_WHOLE_FILE_BYTES)]
+    xmp_whole_file_bytes: usize,
+
+    /// Only select files captured on or after this date (YYYY-MM-DD)
+    #[arg(long)]
+    after: Option<NaiveDate>,
+
+    /// Only select files captured before this date (YYYY-MM-DD)
+    #[arg(long)]
+    before: Option<NaiveDate>,
+
+    /// Select files without a capture date when filtering by --after/--before
+    #[arg(long, default_value_t = false)]
+    include_undated: bool,
+
+    /// Only select files modified at or after this date (YYYY-MM-DD) or
+    /// duration ago, e.g. `24h` or `7d`
+    #[arg(long, value_parser = parse_time_bound)]
+    newer_than: Option<NaiveDateTime>,
+
+    /// Only select files modified before this date (YYYY-MM-DD) or duration
+    /// ago, e.g. `24h` or `7d`
+    #[arg(long, value_parser = parse_time_bound)]
+    older_than: Option<NaiveDateTime>,
+
+    /// Only select files whose camera make or model contains this text (case-insensitive)
+    #[arg(long)]
+    camera: Option<String>,
+
+    /// Only select files whose lens model contains this text (case-insensitive)
+    #[arg(long)]
+    lens: Option<String>,
+
+    /// Only select images whose EXIF orientation flag is not normal, i.e. files
+    /// with rotation that wasn't baked into the pixels yet
+    #[arg(long, default_value_t = false)]
+    fix_orientation: bool,
+
+    /// Only select files with GPS location data
+    #[arg(long, default_value_t = false, conflicts_with = "no_gps")]
+    has_gps: bool,
+
+    /// Only select files without GPS location data
+    #[arg(long, default_value_t = false)]
+    no_gps: bool,
+
+    /// Only select files shot at this ISO or higher
+    #[arg(long)]
+    min_iso: Option<i32>,
+
+    /// Only select files shot at this ISO or lower
+    #[arg(long)]
+    max_iso: Option<i32>,
+
+    /// Only select files shot at this f-number or narrower, e.g. `8`
+    #[arg(long)]
+    min_fnumber: Option<f64>,
+
+    /// Only select files shot at this f-number or wider, e.g. `2.8`
+    #[arg(long)]
+    max_fnumber: Option<f64>,
+
+    /// Only select files exposed for at least this long, e.g. `1/60` or `2`
+    #[arg(long, value_parser = parse_exposure_time)]
+    min_exposure_time: Option<f64>,
+
+    /// Only select files exposed for at most this long, e.g. `1/1000`
+    #[arg(long, value_parser = parse_exposure_time)]
+    max_exposure_time: Option<f64>,
+
+    /// Select files without the ISO, f-number or exposure time tag when filtering by it
+    #[arg(long, default_value_t = false)]
+    include_missing_exposure: bool,
+
+    /// Place files in `YYYY/YYYY-MM-DD` folders under the destination based on their
+    /// capture date, or modification date when the capture date is missing
+    #[arg(long, default_value_t = false)]
+    organize_by_date: bool,
+
+    /// Place files in the destination subdirectory of the first rule they match, e.g.
+    /// `rating>=5:Portfolio,rating>=3:Review`. Conditions compare the rating (unrated
+    /// files per --treat-missing-as) or the label, `*` matches every file. Files matching
+    /// no rule are skipped
+    #[arg(long, value_parser = Routes::parse)]
+    route: Option<Routes>,
+
+    /// Place all files directly in the destination instead of mirroring the source
+    /// layout. Combine with `--on-collision rename` to keep files with the same name
+    #[arg(long, default_value_t = false, conflicts_with = "organize_by_date")]
+    flatten: bool,
+
+    /// Only act on the first of the selected files with identical content
+    #[arg(long, default_value_t = false)]
+    dedup: bool,
+
+    /// Act on every frame of a burst when one of its frames passes the filter. Frames are
+    /// files in the same directory named `<base>_<counter>`, e.g. `IMG_1234_001.jpg`
+    #[arg(long, default_value_t = false)]
+    group_bursts: bool,
+
+    /// Only act on the highest rated of the frames of each burst that passed the filter
+    #[arg(long, default_value_t = false, requires = "group_bursts")]
+    keep_best_per_burst: bool,
+
+    /// Read newline-separated file paths from stdin instead of walking the source directory
+    #[arg(long, default_value_t = false)]
+    from_stdin: bool,
+
+    /// Move or copy .xmp, .pp3 and .aae sidecar files together with their images
+    #[arg(long, default_value_t = false)]
+    copy_sidecars: bool,
+
+    /// Move deleted files into this directory, preserving their relative path,
+    /// instead of removing them
+    #[arg(long)]
+    trash: Option<PathBuf>,
+
+    /// Append every file operation to this journal so it can be reverted with `undo`
+    #[arg(long)]
+    journal: Option<PathBuf>,
+
+    /// Skip the files the journal records as already moved, copied or deleted, to continue
+    /// an interrupted run with the same options
+    #[arg(long, default_value_t = false, requires = "journal")]
+    resume: bool,
+
+    /// Append a CSV row with the source, destination, rating, label and capture date of
+    /// every moved, copied, deleted or tagged file to this manifest, also in --dry-run
+    #[arg(long)]
+    manifest: Option<PathBuf>,
+
+    /// Saved --dry-run output to run with `apply-plan`
+    #[arg(long)]
+    plan: Option<PathBuf>,
+
+    /// Reuse metadata read by previous runs from this file, only reopening changed files
+    #[arg(long)]
+    cache: Option<PathBuf>,
+
+    /// Write the files whose metadata couldn't be read as JSON to this file
+    #[arg(long)]
+    errors_file: Option<PathBuf>,
+
+    /// Write the run summary as JSON to this file
+    #[arg(long)]
+    report: Option<PathBuf>,
+
+    /// Read default options from this TOML file instead of `./exif.toml`. Flags it sets are
+    /// turned off with `--flag=false`
+    #[arg(long = "config")]
+    config_file: Option<PathBuf>,
+}
+
+impl Cli {
+    fn config(&self) -> Config {
+        Config {
+            src: self.src.clone().expect("Did not specify source path"),
+            raw_src: self.raw_src.clone(),
+            flat_raw_src: self.flat_raw_src,
+            exclude: self.exclude.clone(),
+            flip_exclusion: self.flip_exclusion,
+            exclude_recursive: self.exclude_recursive,
+            include_videos: self.include_videos,
+            include_raw_only: self.include_raw_only,
+            orphan_raws: self.orphan_raws,
+            match_raws: self.match_raws,
+            raw_stem: self.raw_stem,
+            max_depth: self.max_depth,
+            max_scan: self.max_scan,
+            follow_symlinks: self.follow_symlinks,
+            include_glob: self.include_glob.clone(),
+            exclude_glob: self.exclude_glob.clone(),
+            no_ignore: self.no_ignore,
+            skip_dir: None,
+            group_bursts: self.group_bursts,
+            extensions: self.ext.clone(),
+            exclude_extensions: self.exclude_ext.clone(),
+        }
+    }
+
+    fn filter(&self) -> Filter {
+        // Every rating is ranked by --top-n-per-dir instead of compared against the threshold
+        let (threshold, comparison_command) = match self.top_n_per_dir {
+            Some(_) => (i32::MIN, ComparisonCommand::MoreEqual),
+            None => (self.threshold, self.comparison_command.clone()),
+        };
+        Filter {
+            threshold,
+            comparison_command,
+            min_rating: self.min_rating,
+            max_rating: self.max_rating,
+            unrated: self.unrated,
+            treat_missing_as: self.treat_missing_as,
+            label: self.label.clone(),
+            label_match: self.label_match.clone(),
+            flag: self.flag,
+            include_tag: self.include_tag.clone(),
+            ignore_tag: self.ignore_tag.clone(),
+            after: self.after,
+            before: self.before,
+            include_undated: self.include_undated,
+            newer_than: self.newer_than,
+            older_than: self.older_than,
+            camera: self.camera.clone(),
+            lens: self.lens.clone(),
+            fix_orientation: self.fix_orientation,
+            has_gps: match (self.has_gps, self.no_gps) {
+                (true, _) => Some(true),
+                (_, true) => Some(false),
+                _ => None,
+            },
+            min_iso: self.min_iso,
+            max_iso: self.max_iso,
+            min_fnumber: self.min_fnumber,
+            max_fnumber: self.max_fnumber,
+            min_exposure_time: self.min_exposure_time,
+            max_exposure_time: self.max_exposure_time,
+            include_missing_exposure: self.include_missing_exposure,
+            inverse: self.inverse,
+            prefer_sidecar: self.prefer_sidecar,
+            rating_source: self.rating_source,
+            rating_from: self.rating_from,
+            rating_tag: self.rating_tag.clone(),
+            xmp_search: self.xmp_search(),
+        }
+    }
+
+    fn xmp_search(&self) -> XmpSearch {
+        XmpSearch {
+            tail_bytes: self.xmp_tail_bytes,
+            max_bytes: self.xmp_max_bytes,
+            whole_file_bytes: self.xmp_whole_file_bytes,
+        }
+    }
+}
+
+impl Display for OutputFormat {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            OutputFormat::Text => write!(f, "text"),
+            OutputFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, PartialEq, Debug)]
+enum OutputFormat {
+    Text,
+    Json,
 }
 
-#[derive(Subcommand, PartialEq)]
-enum FileCommand {
-    Move,
-    Copy,
-    Delete,
-    Print,
-    DeleteRaws,
-    CopyRaws,
-    CopyRatingToRaws,
+#[derive(ValueEnum, Clone, PartialEq, Debug)]
+enum SortKey {
+    Path,
+    /// Highest rating first
+    Rating,
+    /// Oldest capture date first, undated files last
+    Date,
 }
 
-impl Display for ComparisonCommand {
+impl Display for SortKey {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match self {
-            ComparisonCommand::MoreEqual => write!(f, "more-equal"),
-            ComparisonCommand::LessEqual => write!(f, "less-equal"),
-            ComparisonCommand::Equal => write!(f, "equal"),
+            SortKey::Path => write!(f, "path"),
+            SortKey::Rating => write!(f, "rating"),
+            SortKey::Date => write!(f, "date"),
         }
     }
 }
 
-#[derive(ValueEnum, Clone, Debug)]
-enum ComparisonCommand {
-    MoreEqual,
-    LessEqual,
-    Equal,
+#[derive(Serialize)]
+struct PrintRecord {
+    path: PathBuf,
+    raw_paths: Vec<PathBuf>,
+    rating: Option<i32>,
+    label: Option<String>,
+    capture_date: Option<NaiveDateTime>,
+    /// Embedded and sidecar rating with --find-conflicts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    conflict: Option<RatingConflict>,
+    /// Raw without a matching image, selected by --orphan-raws
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    orphan_raw: bool,
 }
 
-#[derive(Clone, Eq, PartialEq, Debug)]
-struct Entry {
+#[derive(Serialize)]
+struct ErrorRecord {
     path: PathBuf,
-    raw_path: Option<PathBuf>,
+    category: ErrorCategory,
+    error: String,
+}
+
+#[derive(Serialize, Default)]
+struct RunSummary {
+    scanned: usize,
+    /// Directories that couldn't be read during the traversal
+    skipped_dirs: usize,
+    /// Files the journal records as done, left out by --resume
+    #[serde(skip_serializing_if = "Option::is_none")]
+    skipped_completed: Option<usize>,
+    passed_filter: usize,
+    skipped_duplicate: usize,
+    /// Frames added by --group-bursts, or left out by --keep-best-per-burst
+    #[serde(skip_serializing_if = "Option::is_none")]
+    added_burst_frames: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    skipped_burst_frames: Option<usize>,
+    /// Files whose embedded and sidecar ratings agree, left out by --find-conflicts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    skipped_without_conflict: Option<usize>,
+    /// Highest rated files of their directory left out by --top-n-per-dir
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kept_top_rated: Option<usize>,
+    /// Selected files left out by --skip
+    skipped_offset: usize,
+    /// Selected files left out by --limit
+    skipped_limit: usize,
+    /// Selected files with at least one matched raw, only counted for raw commands
+    #[serde(skip_serializing_if = "Option::is_none")]
+    with_raws: Option<usize>,
+    acted: usize,
+    skipped_existing: usize,
+    /// Files the user chose not to act on in --interactive mode
+    declined: usize,
+    /// Files left alone by --label-to-rating as their label isn't mapped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    skipped_unmapped_label: Option<usize>,
+    /// Files matching none of the --route rules
+    #[serde(skip_serializing_if = "Option::is_none")]
+    skipped_unrouted: Option<usize>,
+    errored: usize,
+    /// Errored files whose copy didn't match the original with --verify
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failed_verification: Option<usize>,
+    /// Number of files that passed the filter for each rating value
+    ratings: BTreeMap<i32, usize>,
+    /// Files that passed the filter without a rating tag
+    unrated: usize,
+    /// Files a dry run would place in each destination directory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destinations: Option<BTreeMap<PathBuf, DestinationTotal>>,
 }
 
-impl Entry {
-    fn new(path: PathBuf) -> Entry {
-        Entry {
-            path,
-            raw_path: None,
+#[derive(Serialize, Default)]
+struct DestinationTotal {
+    files: usize,
+    bytes: u64,
+}
+
+impl Display for RunSummary {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(f, "Scanned: {}", self.scanned)?;
+        if self.skipped_dirs > 0 {
+            writeln!(f, "Skipped unreadable directories: {}", self.skipped_dirs)?;
+        }
+        if let Some(skipped_completed) = self.skipped_completed {
+            writeln!(f, "Skipped completed: {}", skipped_completed)?;
+        }
+        writeln!(f, "Passed filter: {}", self.passed_filter)?;
+        if let Some(added_burst_frames) = self.added_burst_frames {
+            writeln!(f, "Added burst frames: {}", added_burst_frames)?;
+        }
+        if let Some(skipped_burst_frames) = self.skipped_burst_frames {
+            writeln!(f, "Skipped burst frames: {}", skipped_burst_frames)?;
+        }
+        if let Some(skipped_without_conflict) = self.skipped_without_conflict {
+            writeln!(f, "Skipped without conflict: {}", skipped_without_conflict)?;
+        }
+        if let Some(kept_top_rated) = self.kept_top_rated {
+            writeln!(f, "Kept as top rated: {}", kept_top_rated)?;
+        }
+        if self.skipped_duplicate > 0 {
+            writeln!(f, "Skipped duplicate: {}", self.skipped_duplicate)?;
+        }
+        if self.skipped_offset > 0 {
+            writeln!(f, "Skipped before offset: {}", self.skipped_offset)?;
+        }
+        if self.skipped_limit > 0 {
+            writeln!(f, "Skipped over limit: {}", self.skipped_limit)?;
+        }
+        if let Some(with_raws) = self.with_raws {
+            writeln!(f, "With matched raws: {}", with_raws)?;
+        }
+        writeln!(f, "Acted: {}", self.acted)?;
+        writeln!(f, "Skipped existing: {}", self.skipped_existing)?;
+        if self.declined > 0 {
+            writeln!(f, "Declined: {}", self.declined)?;
+        }
+        if let Some(skipped_unmapped_label) = self.skipped_unmapped_label {
+            writeln!(f, "Skipped unmapped label: {}", skipped_unmapped_label)?;
+        }
+        if let Some(skipped_unrouted) = self.skipped_unrouted {
+            writeln!(f, "Skipped unrouted: {}", skipped_unrouted)?;
+        }
+        writeln!(f, "Errored: {}", self.errored)?;
+        if let Some(failed_verification) = self.failed_verification {
+            writeln!(f, "Failed verification: {}", failed_verification)?;
+        }
+        write!(f, "Ratings:")?;
+        for (rating, count) in &self.ratings {
+            write!(f, " {rating}: {count}")?;
+        }
+        if self.unrated > 0 {
+            write!(f, " unrated: {}", self.unrated)?;
+        }
+        if let Some(destinations) = &self.destinations {
+            write!(f, "\nDestinations:")?;
+            for (dir, total) in destinations {
+                write!(
+                    f,
+                    "\n  {}: {} files, {}",
+                    dir.display(),
+                    total.files,
+                    format_size(total.bytes)
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Default)]
+struct RatingStats {
+    files: usize,
+    errored: usize,
+    ratings: BTreeMap<i32, usize>,
+    unrated: usize,
+    labels: BTreeMap<String, usize>,
+    unlabeled: usize,
+}
+
+impl RatingStats {
+    fn new() -> RatingStats {
+        RatingStats {
+            // Show every star count even when no file has it
+            ratings: (0..=5).map(|rating| (rating, 0)).collect(),
+            ..Default::default()
         }
     }
 
-    fn new_with_raw(path: PathBuf, raw_path: PathBuf) -> Entry {
-        Entry {
-            path,
-            raw_path: Some(raw_path),
+    fn add(&mut self, meta: &FileMeta) {
+        self.files += 1;
+        match meta.rating {
+            Some(rating) => *self.ratings.entry(rating).or_default() += 1,
+            None => self.unrated += 1,
+        }
+        match &meta.label {
+            Some(label) => *self.labels.entry(label.clone()).or_default() += 1,
+            None => self.unlabeled += 1,
         }
     }
 }
 
-impl Display for Entry {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!(" p: {:?}", self.path))?;
-        if let Some(raw_path) = &self.raw_path {
-            f.write_fmt(format_args!(" r: {:?}", raw_path))?;
+impl Display for RatingStats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        const BAR_WIDTH: usize = 40;
+        let max_count = self.ratings.values().copied().max().unwrap_or(0).max(1);
+        writeln!(f, "Rating histogram of {} files", self.files)?;
+        for (rating, count) in &self.ratings {
+            let bar = "#".repeat(count * BAR_WIDTH / max_count);
+            writeln!(f, "{rating:>2} | {bar:<BAR_WIDTH$} {count}")?;
+        }
+        writeln!(f, "Unrated: {}", self.unrated)?;
+        writeln!(f, "Labels:")?;
+        for (label, count) in &self.labels {
+            writeln!(f, "  {label}: {count}")?;
+        }
+        write!(f, "  (none): {}", self.unlabeled)?;
+        if self.errored > 0 {
+            write!(f, "\nErrored: {}", self.errored)?;
         }
         Ok(())
     }
 }
 
-fn main() {
-    let cli: Cli = Cli::parse();
+enum Confirmation {
+    Yes,
+    No,
+    All,
+    Quit,
+}
+
+/// Prompts on stderr until a valid answer is read, end of input counts as quit
+fn confirm(command_name: &str, path: &Entry, rating: Option<i32>) -> io::Result<Confirmation> {
+    loop {
+        eprint!(
+            "{command_name} {path} (rating {})? [y/n/a/q] ",
+            rating_text(rating)
+        );
+        io::stderr().flush()?;
+        let mut answer = String::new();
+        if io::stdin().read_line(&mut answer)? == 0 {
+            return Ok(Confirmation::Quit);
+        }
+        match answer.trim().to_lowercase().as_str() {
+            "y" | "yes" => return Ok(Confirmation::Yes),
+            "n" | "no" => return Ok(Confirmation::No),
+            "a" | "all" => return Ok(Confirmation::All),
+            "q" | "quit" => return Ok(Confirmation::Quit),
+            _ => eprintln!("Please answer y(es), n(o), a(ll) or q(uit)"),
+        }
+    }
+}
+
+/// Looked up in the working directory when --config isn't given
+const DEFAULT_CONFIG_FILE: &str = "exif.toml";
+
+/// Option defaults keyed by their long flag name, command line arguments take precedence
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct ConfigFile {
+    threshold: Option<i32>,
+    inverse: Option<bool>,
+    verbose: Option<Verbosity>,
+    quiet: Option<bool>,
+    dest: Option<PathBuf>,
+    raw_src: Option<PathBuf>,
+    flat_raw_src: Option<bool>,
+    exclude: Option<Vec<String>>,
+    flip_exclusion: Option<bool>,
+    exclude_recursive: Option<bool>,
+    match_raws: Option<bool>,
+    raw_stem: Option<String>,
+    include_videos: Option<bool>,
+    include_raw_only: Option<bool>,
+    orphan_raws: Option<bool>,
+    follow_symlinks: Option<bool>,
+    include_glob: Option<Vec<String>>,
+    exclude_glob: Option<Vec<String>>,
+    ext: Option<Vec<String>>,
+    exclude_ext: Option<Vec<String>>,
+    no_ignore: Option<bool>,
+    label: Option<Vec<String>>,
+    label_match: Option<String>,
+    flag: Option<String>,
+    ignore_tag: Option<String>,
+    include_tag: Option<String>,
+    on_collision: Option<String>,
+    comparison_command: Option<String>,
+    jobs: Option<usize>,
+    max_scan: Option<usize>,
+    format: Option<String>,
+    prefer_sidecar: Option<bool>,
+    rating_tag: Option<String>,
+    xmp_tail_bytes: Option<usize>,
+    xmp_max_bytes: Option<usize>,
+    xmp_whole_file_bytes: Option<usize>,
+    rating_source: Option<String>,
+    rating_from: Option<String>,
+    treat_missing_as: Option<String>,
+    include_undated: Option<bool>,
+    include_missing_exposure: Option<bool>,
+    copy_sidecars: Option<bool>,
+    preserve_times: Option<bool>,
+    trash: Option<PathBuf>,
+    journal: Option<PathBuf>,
+    cache: Option<PathBuf>,
+}
+
+/// `verbose` of config files, a level or `true` for level 1 as before -vv existed
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Verbosity {
+    Enabled(bool),
+    Level(u8),
+}
+
+impl Verbosity {
+    fn level(&self) -> u8 {
+        match *self {
+            Verbosity::Enabled(enabled) => enabled.into(),
+            Verbosity::Level(level) => level,
+        }
+    }
+}
+
+impl ConfigFile {
+    fn load(path: &Path) -> Result<ConfigFile> {
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// Arguments for the options that weren't given on the command line
+    fn args(self, command_line: &CommandLine) -> Vec<OsString> {
+        let mut args = Vec::new();
+        let from_command_line = |id: &str| command_line.contains(id);
+        let flag = |id: &str| OsString::from(format!("--{}", id.replace('_', "-")));
+        let option = |id: &str, value: &OsStr| {
+            let mut arg = flag(id);
+            arg.push("=");
+            arg.push(value);
+            arg
+        };
+        macro_rules! value {
+            ($($field:ident),* $(,)?) => {$(
+                if let Some(value) = self.$field {
+                    if !from_command_line(stringify!($field)) {
+                        args.push(option(stringify!($field), value.to_string().as_ref()));
+                    }
+                }
+            )*};
+        }
+        macro_rules! path {
+            ($($field:ident),* $(,)?) => {$(
+                if let Some(value) = self.$field {
+                    if !from_command_line(stringify!($field)) {
+                        args.push(option(stringify!($field), value.as_os_str()));
+                    }
+                }
+            )*};
+        }
+        macro_rules! values {
+            ($($field:ident),* $(,)?) => {$(
+                if let Some(values) = self.$field {
+                    if !from_command_line(stringify!($field)) {
+                        for value in values {
+                            args.push(option(stringify!($field), value.as_ref()));
+                        }
+                    }
+                }
+            )*};
+        }
+        // Flags set to false are left out, so they don't conflict with other options
+        macro_rules! flags {
+            ($($field:ident),* $(,)?) => {$(
+                if self.$field == Some(true) && !from_command_line(stringify!($field)) {
+                    args.push(flag(stringify!($field)));
+                }
+            )*};
+        }
+        value!(
+            threshold,
+            raw_stem,
+            label_match,
+            flag,
+            ignore_tag,
+            include_tag,
+            on_collision,
+            comparison_command,
+            jobs,
+            max_scan,
+            format,
+            rating_tag,
+            xmp_tail_bytes,
+            xmp_max_bytes,
+            xmp_whole_file_bytes,
+            rating_source,
+            rating_from,
+            treat_missing_as,
+        );
+        path!(dest, raw_src, trash, journal, cache);
+        values!(exclude, include_glob, exclude_glob, ext, exclude_ext, label);
+        flags!(
+            inverse,
+            quiet,
+            flat_raw_src,
+            flip_exclusion,
+            exclude_recursive,
+            match_raws,
+            include_videos,
+            include_raw_only,
+            orphan_raws,
+            follow_symlinks,
+            no_ignore,
+            prefer_sidecar,
+            include_undated,
+            include_missing_exposure,
+            copy_sidecars,
+            preserve_times,
+        );
+        if let Some(verbose) = self.verbose {
+            if !from_command_line("verbose") {
+                args.extend((0..verbose.level()).map(|_| flag("verbose")));
+            }
+        }
+        args
+    }
+
+    /// Parses the command line again with the config file options in front of it, so clap
+    /// checks their values, conflicts and requirements like those given on the command line
+    fn parse(self, command_line: &CommandLine) -> Result<Cli> {
+        let Some((bin_name, args)) = command_line.args.split_first() else {
+            return Ok(Cli::from_arg_matches(&command_line.matches)?);
+        };
+        let args = iter::once(bin_name.clone())
+            .chain(self.args(command_line))
+            .chain(args.iter().cloned());
+        Ok(Cli::try_parse_from(args)?)
+    }
+}
+
+/// The parsed command line, kept to parse it again with the config file options
+struct CommandLine {
+    args: Vec<OsString>,
+    matches: ArgMatches,
+    /// Flags turned off with `--flag=false`, which only overrides the config file. They're
+    /// left out of `args` so they don't conflict with other options
+    turned_off: HashSet<String>,
+}
+
+impl CommandLine {
+    fn parse(args: impl IntoIterator<Item = OsString>) -> clap::error::Result<CommandLine> {
+        let mut command = Cli::command();
+        let turned_off_args: HashMap<OsString, String> = command
+            .get_arguments()
+            .filter(|arg| matches!(arg.get_action(), ArgAction::SetTrue))
+            .flat_map(|arg| {
+                let id = arg.get_id().to_string();
+                let long = arg.get_long().map(|long| format!("--{long}=false"));
+                let short = arg.get_short().map(|short| format!("-{short}=false"));
+                long.into_iter()
+                    .chain(short)
+                    .map(move |name| (OsString::from(name), id.clone()))
+            })
+            .collect();
+        let mut turned_off = HashSet::new();
+        let args: Vec<OsString> = args
+            .into_iter()
+            .filter(|arg| match turned_off_args.get(arg) {
+                Some(id) => {
+                    turned_off.insert(id.clone());
+                    false
+                }
+                None => true,
+            })
+            .collect();
+        let matches = command.try_get_matches_from_mut(&args)?;
+        Ok(CommandLine {
+            args,
+            matches,
+            turned_off,
+        })
+    }
+
+    fn contains(&self, id: &str) -> bool {
+        self.matches.value_source(id) == Some(ValueSource::CommandLine)
+            || self.turned_off.contains(id)
+    }
+}
+
+/// Parses the command line with --config, or `./exif.toml` when it exists
+fn apply_config_file(cli: Cli, command_line: &CommandLine) -> Result<Cli> {
+    let path = match &cli.config_file {
+        Some(path) => path.clone(),
+        None => match Path::new(DEFAULT_CONFIG_FILE) {
+            path if path.exists() => path.to_path_buf(),
+            _ => return Ok(cli),
+        },
+    };
+    ConfigFile::load(&path)
+        .and_then(|config_file| config_file.parse(command_line))
+        .with_context(|| format!("Failed to apply config file {path:?}"))
+}
+
+/// Exit code when at least one file failed to be processed
+const EXIT_FILE_ERRORS: u8 = 1;
+/// Exit code for invalid arguments and setup failures, matching clap's usage errors
+const EXIT_SETUP_ERROR: u8 = 2;
+
+fn sort_entries(entries: &mut [(Entry, FileMeta)], sort: &SortKey, reverse: bool) {
+    match sort {
+        SortKey::Path => entries.sort_by(|(a, _), (b, _)| a.path.cmp(&b.path)),
+        SortKey::Rating => entries.sort_by_key(|(_, meta)| Reverse(meta.rating)),
+        SortKey::Date => {
+            entries.sort_by_key(|(_, meta)| (meta.capture_date.is_none(), meta.capture_date))
+        }
+    }
+    if reverse {
+        entries.reverse();
+    }
+}
+
+/// Diagnostics go to stderr, warnings and errors are prefixed with their level
+fn init_logger(verbose: u8, quiet: bool) {
+    let level = match verbose {
+        _ if quiet => LevelFilter::Error,
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        _ => LevelFilter::Debug,
+    };
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .format(|buf, record| match record.level() {
+            Level::Error => writeln!(buf, "Error: {}", record.args()),
+            Level::Warn => writeln!(buf, "Warning: {}", record.args()),
+            _ => writeln!(buf, "{}", record.args()),
+        })
+        .init();
+}
+
+fn main() -> ExitCode {
+    let command_line = CommandLine::parse(env::args_os()).unwrap_or_else(|e| e.exit());
+    let cli = Cli::from_arg_matches(&command_line.matches).unwrap_or_else(|e| e.exit());
+    let res = apply_config_file(cli, &command_line).and_then(|cli| {
+        init_logger(cli.verbose, cli.quiet);
+        run(cli)
+    });
+    match res {
+        Ok(exit_code) => exit_code,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            ExitCode::from(EXIT_SETUP_ERROR)
+        }
+    }
+}
+
+fn run(cli: Cli) -> Result<ExitCode> {
+    // Completions don't touch any file, so they work without the gexiv2 library
+    if let FileCommand::Completions { shell } = cli.command {
+        let bin_name = env!("CARGO_BIN_NAME");
+        clap_complete::generate(shell, &mut Cli::command(), bin_name, &mut io::stdout());
+        return Ok(ExitCode::SUCCESS);
+    }
+    if cli.command == FileCommand::Manpage {
+        let man = clap_mangen::Man::new(Cli::command().name(env!("CARGO_BIN_NAME")));
+        man.render(&mut io::stdout())
+            .context("Failed to write man page")?;
+        return Ok(ExitCode::SUCCESS);
+    }
 
-    rexiv2::initialize().expect("Unable to initialize rexiv2");
+    rexiv2::initialize().map_err(|e| {
+        anyhow!("Unable to initialize rexiv2 ({e}), make sure the gexiv2 library is installed")
+    })?;
 
     let command_name = match cli.command {
         FileCommand::Move => "Moving",
@@ -144,548 +1154,1124 @@ fn main() {
         FileCommand::DeleteRaws => "Deleting raw file",
         FileCommand::CopyRaws => "Copying raw file",
         FileCommand::CopyRatingToRaws => "Copying rating to raw file",
+        FileCommand::SetRating => "Setting rating",
+        FileCommand::SetLabel => "Setting label",
+        FileCommand::StripGps => "Removing GPS data",
+        FileCommand::Rename => "Renaming",
+        FileCommand::Undo => "Undoing",
+        FileCommand::Stats => "Collecting statistics",
+        FileCommand::ApplyPlan => "Applying plan",
+        FileCommand::Completions { .. } => "Generating completions",
+        FileCommand::Manpage => "Generating man page",
+    };
+
+    if cli.command == FileCommand::Undo {
+        let journal_path = cli
+            .journal
+            .context("Undo operation requires journal option (--journal)")?;
+        let failed_count =
+            undo_journal(&journal_path, cli.dry_run).context("Failed to read journal")?;
+        if !cli.quiet {
+            eprintln!("{command_name}: {failed_count} failed");
+        }
+        return Ok(exit_code(failed_count));
+    }
+
+    // Read before this run appends to the journal
+    let completed_sources: HashSet<PathBuf> = match (&cli.journal, cli.resume) {
+        (Some(journal_path), true) => {
+            journaled_sources(journal_path).context("Failed to read journal")?
+        }
+        _ => HashSet::new(),
+    };
+    let mut journal: Option<Journal> = cli
+        .journal
+        .as_ref()
+        .map(|journal_path| Journal::open(journal_path).context("Failed to open journal"))
+        .transpose()?;
+    let mut manifest: Option<Manifest> = cli
+        .manifest
+        .as_ref()
+        .map(|manifest_path| Manifest::open(manifest_path).context("Failed to open manifest"))
+        .transpose()?;
+    let mut ctx = CommandContext {
+        dry_run: cli.dry_run,
+        on_collision: match cli.r#override {
+            true => CollisionMode::Overwrite,
+            false => cli.on_collision.clone(),
+        },
+        preserve_times: cli.preserve_times,
+        strip_metadata: cli.strip_metadata,
+        verify: cli.verify,
+        copy_sidecars: cli.copy_sidecars,
+        journal: journal.as_mut(),
+        manifest: manifest.as_mut(),
+    };
+
+    if cli.command == FileCommand::ApplyPlan {
+        let plan_path = cli
+            .plan
+            .context("Apply plan operation requires plan option (--plan)")?;
+        // Plan lines have no metadata to list in the manifest
+        let failed_count = apply_plan(
+            &plan_path,
+            &mut CommandContext {
+                manifest: None,
+                ..ctx
+            },
+        )
+        .context("Failed to read plan")?;
+        if !cli.quiet {
+            eprintln!("{command_name}: {failed_count} failed");
+        }
+        return Ok(exit_code(failed_count));
+    }
+
+    let Some(search_path) = cli.src.clone() else {
+        anyhow::bail!("Source path must be specified");
     };
 
-    let search_path = cli.src;
+    ensure!(search_path.is_dir(), "Source path must be a directory");
 
-    assert!(search_path.is_dir(), "Source path must be a directory");
+    let uses_trash = cli.trash.is_some()
+        && [FileCommand::Delete, FileCommand::DeleteRaws].contains(&cli.command);
 
-    let output_path: Option<PathBuf> = cli.dest;
+    let output_path: Option<PathBuf> = match uses_trash {
+        true => cli.trash.clone(),
+        false => cli.dest.clone(),
+    };
 
-    if [
+    let raw_command = [
         FileCommand::DeleteRaws,
         FileCommand::CopyRaws,
         FileCommand::CopyRatingToRaws,
     ]
-    .contains(&cli.command)
-    {
-        assert!(
+    .contains(&cli.command);
+    if raw_command {
+        ensure!(
             cli.match_raws,
-            "Raw file operation requires match_raws option (-m)"
+            "Raw file operation requires the --match-raws (-m) option"
+        );
+    }
+
+    if cli.command == FileCommand::SetRating {
+        ensure!(
+            cli.set_rating.is_some() || cli.label_to_rating.is_some(),
+            "Set rating operation requires set_rating option (--set-rating) or \
+             label_to_rating option (--label-to-rating)"
         );
     }
 
+    if cli.command == FileCommand::SetLabel {
+        ensure!(
+            cli.set_label.is_some(),
+            "Set label operation requires set_label option (--set-label)"
+        );
+    }
+
+    if cli.command == FileCommand::Rename {
+        ensure!(
+            cli.name_template.is_some(),
+            "Rename operation requires name_template option (--name-template)"
+        );
+    }
+
+    ensure!(
+        !cli.strip_metadata || matches!(cli.command, FileCommand::Copy | FileCommand::Move),
+        "Stripping metadata requires the copy or move command"
+    );
+
+    ensure!(
+        !cli.find_conflicts || cli.command == FileCommand::Print,
+        "Finding conflicts requires the print command"
+    );
+
+    ensure!(
+        cli.route.is_none()
+            || [FileCommand::Move, FileCommand::Copy, FileCommand::CopyRaws].contains(&cli.command),
+        "Routing requires the move, copy or copy-raws command"
+    );
+
+    // Dry-run output is a shell script, listed paths would be run as commands
+    ensure!(
+        !(cli.dry_run && cli.command == FileCommand::Print),
+        "Print doesn't modify files and can't be combined with --dry-run"
+    );
+
     let requires_destination = cli.command == FileCommand::Move
         || cli.command == FileCommand::Copy
-        || cli.command == FileCommand::CopyRaws;
-
-    if requires_destination {
-        assert!(output_path.is_some(), "Destination path must be specified");
-        if output_path.clone().unwrap().exists() {
-            assert!(
-                output_path.clone().unwrap().is_dir(),
-                "Output path must be a directory"
-            );
+        || cli.command == FileCommand::CopyRaws
+        || uses_trash;
+    // Rename works in place unless a destination is given
+    let uses_destination =
+        requires_destination || (cli.command == FileCommand::Rename && output_path.is_some());
+
+    if uses_destination {
+        let Some(output_path) = output_path.as_ref() else {
+            anyhow::bail!("Destination path must be specified");
+        };
+        if cli.dry_run && !output_path.exists() {
+            println!("mkdir -p {}", shell_quote(output_path));
         } else {
-            fs::create_dir(output_path.clone().unwrap().clone())
-                .expect("Failed to create output directory");
-        }
-    }
-
-    let mut all_paths: Vec<Entry> = Vec::new();
-    visit_dirs(
-        search_path.as_ref(),
-        &mut all_paths,
-        0,
-        cli.exclude,
-        cli.flip_exclusion,
-        cli.include_videos,
-        cli.match_raws,
-        cli.raw_src.as_ref(),
-        search_path.as_ref(),
-        cli.verbose,
-    )
-    .expect("Failed to iterate over directories");
-
-    for path in all_paths {
-        let relative_path = path
-            .path
-            .strip_prefix(search_path.clone())
-            .expect(format!("Failed to strip root prefix of file {:?}", path).as_str());
+            create_output_dir(output_path).context("Failed to create output directory")?;
+        }
+    }
 
-        let res: Result<i32> = get_rating(path.path.clone());
-        let Ok(rating) = res else {
-            eprintln!(
-                "Skipping {path:?} due to {}",
-                res.err().unwrap_or(anyhow!("Unknown error")).to_string()
+    // Mirroring the source layout into itself would turn every operation into a self-move
+    if let Some(output_path) = output_path.as_ref().filter(|_| requires_destination) {
+        let keeps_layout = !cli.organize_by_date && !cli.flatten;
+        if keeps_layout && output_path.exists() {
+            ensure!(
+                fs::canonicalize(output_path)? != fs::canonicalize(&search_path)?,
+                "Destination path {output_path:?} is the source directory, pass a different \
+                 --dest or reorganize with --organize-by-date or --flatten"
             );
-            continue;
-        };
+        }
+    }
 
-        let pass_label_check = if let Some(ref label) = cli.label {
-            let res: Result<Option<String>, String> = get_label(path.path.clone());
-            let Ok(label_res) = res else {
-                eprintln!(
-                    "Skipping {path:?} due to {}",
-                    res.err().unwrap_or("Unknown error".to_string()).to_string()
-                );
-                continue;
-            };
-            match label_res {
-                Some(label_res) => label_res == *label,
-                None => false,
+    let filter = cli.filter();
+    let rating_options = filter.rating_options();
+    let mut config = cli.config();
+    config.skip_dir = output_path.clone().filter(|_| uses_destination);
+    let mut skipped_dirs = 0;
+    let mut all_paths: Vec<Entry> = if cli.from_stdin {
+        let lines = io::stdin()
+            .lines()
+            .collect::<io::Result<Vec<String>>>()
+            .context("Failed to read paths from stdin")?;
+        let paths = lines
+            .into_iter()
+            .filter(|line| !line.trim().is_empty())
+            .map(PathBuf::from);
+        entries_from_paths(paths, &config).context("Failed to collect files from stdin")?
+    } else {
+        let traversal = traverse(&config).context("Failed to iterate over directories")?;
+        skipped_dirs = traversal.skipped_dirs;
+        traversal.entries
+    };
+    let mut skipped_completed = None;
+    if cli.resume {
+        let path_count = all_paths.len();
+        // Entries interrupted halfway are redone, files already at the destination are
+        // skipped by the collision handling
+        all_paths.retain(|entry| {
+            !std::iter::once(&entry.path)
+                .chain(&entry.raw_paths)
+                .all(|path| completed_sources.contains(path))
+        });
+        skipped_completed = Some(path_count - all_paths.len());
+    }
+    if raw_command && all_paths.iter().all(|entry| entry.raw_paths.is_empty()) {
+        warn!(
+            "No raw files were matched, {command_name} will not change anything. \
+             Check --raw-src and that raw files share the name of their images"
+        );
+    }
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(cli.jobs.unwrap_or(0))
+        .build()
+        .context("Failed to build thread pool")?;
+
+    let mut summary = RunSummary {
+        scanned: all_paths.len() + skipped_completed.unwrap_or(0),
+        skipped_dirs,
+        skipped_completed,
+        failed_verification: (cli.verify && !cli.dry_run).then_some(0),
+        skipped_unmapped_label: (cli.command == FileCommand::SetRating
+            && cli.label_to_rating.is_some())
+        .then_some(0),
+        skipped_unrouted: cli.route.as_ref().map(|_| 0),
+        destinations: (cli.dry_run && uses_destination).then(BTreeMap::new),
+        ..Default::default()
+    };
+
+    // Progress is drawn on stderr, keep it out of piped output and verbose logs
+    let progress = match io::stdout().is_terminal() && cli.verbose == 0 && !cli.quiet {
+        true => ProgressBar::new(all_paths.len() as u64).with_style(
+            ProgressStyle::with_template("{bar:40} {pos}/{len} ETA {eta}")
+                .expect("Invalid progress bar template"),
+        ),
+        false => ProgressBar::hidden(),
+    };
+
+    let cache = cli.cache.as_ref().map(|cache_path| {
+        MetadataCache::load(
+            cache_path,
+            &cli.rating_tag,
+            cli.prefer_sidecar,
+            cli.rating_source,
+            cli.xmp_search(),
+        )
+    });
+
+    if cli.command == FileCommand::Stats {
+        let metas: Vec<Result<FileMeta>> = pool.install(|| {
+            all_paths
+                .into_par_iter()
+                .map(|path| {
+                    let res = match cache {
+                        Some(ref cache) => cache.read_metadata(path.path.clone()),
+                        None => read_metadata(
+                            path.path.clone(),
+                            cli.prefer_sidecar,
+                            &cli.rating_tag,
+                            cli.rating_source,
+                            cli.xmp_search(),
+                        ),
+                    };
+                    progress.inc(1);
+                    res.inspect_err(|e| {
+                        progress.suspend(|| warn!("Skipping {:?} due to {e}", path.path))
+                    })
+                })
+                .collect()
+        });
+        progress.finish_and_clear();
+        if let (Some(cache), Some(cache_path)) = (cache, cli.cache.as_ref()) {
+            if let Err(e) = cache.save(cache_path) {
+                warn!("Failed to write cache to {cache_path:?} due to {e}");
             }
-        } else {
-            true
-        };
+        }
 
-        let pass_include_label_check = if let Some(ref include_tag) = cli.include_tag {
-            let res: Result<Option<Vec<String>>, String> = get_tags(path.path.clone());
-            let Ok(tags_res) = res else {
-                eprintln!(
-                    "Skipping {path:?} due to {}",
-                    res.err().unwrap_or("Unknown error".to_string()).to_string()
-                );
-                continue;
-            };
-            match tags_res {
-                Some(tags_res) => tags_res.contains(&include_tag.to_lowercase()),
-                None => false,
+        let mut stats = RatingStats::new();
+        for meta in metas {
+            match meta {
+                Ok(meta) => stats.add(&meta),
+                Err(_) => stats.errored += 1,
             }
-        } else {
-            true
-        };
+        }
+        match cli.format {
+            OutputFormat::Text => println!("{stats}"),
+            OutputFormat::Json => println!(
+                "{}",
+                serde_json::to_string_pretty(&stats).expect("Failed to serialize statistics")
+            ),
+        }
+        return Ok(exit_code(stats.errored));
+    }
 
-        let pass_ignore_label_check = if let Some(ref ignore_tag) = cli.ignore_tag {
-            let res: Result<Option<Vec<String>>, String> = get_tags(path.path.clone());
-            let Ok(tags_res) = res else {
-                eprintln!(
-                    "Skipping {path:?} due to {}",
-                    res.err().unwrap_or("Unknown error".to_string()).to_string()
-                );
-                continue;
-            };
-            match tags_res {
-                Some(tags_res) => !tags_res.contains(&ignore_tag.to_lowercase()),
-                None => false,
+    // Frames of each burst, kept to pull in the frames that didn't pass the filter
+    let mut bursts: HashMap<PathBuf, Vec<Entry>> = HashMap::new();
+    if cli.group_bursts && !cli.keep_best_per_burst {
+        for entry in &all_paths {
+            if let Some(burst) = &entry.burst {
+                bursts.entry(burst.clone()).or_default().push(entry.clone());
             }
-        } else {
-            true
-        };
+        }
+    }
 
-        let pass_treshold_check = match cli.comparison_command {
-            ComparisonCommand::MoreEqual => rating >= cli.threshold,
-            ComparisonCommand::LessEqual => rating <= cli.threshold,
-            ComparisonCommand::Equal => rating == cli.threshold,
-        };
+    let evaluation_errors: Mutex<Vec<ErrorRecord>> = Mutex::new(Vec::new());
+    let mut selected_paths: Vec<(Entry, FileMeta)> = pool.install(|| {
+        all_paths
+            .into_par_iter()
+            .filter_map(|path| {
+                let res = match cache {
+                    Some(ref cache) => evaluate_cached(&path, &filter, cache),
+                    None => evaluate_with_meta(&path, &filter),
+                };
+                progress.inc(1);
+                match res {
+                    Ok(meta) => meta.map(|meta| (path, meta)),
+                    Err(e) => {
+                        progress.suspend(|| warn!("Skipping {path:?} due to {e}"));
+                        evaluation_errors.lock().unwrap().push(ErrorRecord {
+                            path: path.path,
+                            category: ErrorCategory::of(&e),
+                            error: e.to_string(),
+                        });
+                        None
+                    }
+                }
+            })
+            .collect()
+    });
+    progress.finish_and_clear();
+
+    let mut evaluation_errors = evaluation_errors.into_inner().unwrap();
+    if cli.keep_best_per_burst {
+        let selected_count = selected_paths.len();
+        selected_paths = keep_best_per_burst(selected_paths);
+        summary.skipped_burst_frames = Some(selected_count - selected_paths.len());
+    } else if cli.group_bursts {
+        let selected_count = selected_paths.len();
+        add_burst_frames(&mut selected_paths, bursts, |path| {
+            let res = match cache {
+                Some(ref cache) => cache.read_metadata(path.path.clone()),
+                None => read_metadata(
+                    path.path.clone(),
+                    cli.prefer_sidecar,
+                    &cli.rating_tag,
+                    cli.rating_source,
+                    cli.xmp_search(),
+                ),
+            };
+            res.inspect_err(|e| {
+                warn!("Skipping {path:?} due to {e}");
+                evaluation_errors.push(ErrorRecord {
+                    path: path.path.clone(),
+                    category: ErrorCategory::of(e),
+                    error: e.to_string(),
+                });
+            })
+            .ok()
+        });
+        summary.added_burst_frames = Some(selected_paths.len() - selected_count);
+    }
+
+    if let (Some(cache), Some(cache_path)) = (cache, cli.cache.as_ref()) {
+        if let Err(e) = cache.save(cache_path) {
+            warn!("Failed to write cache to {cache_path:?} due to {e}");
+        }
+    }
+    summary.errored = evaluation_errors.len();
+
+    if let Some(errors_path) = cli.errors_file.as_ref() {
+        let errors =
+            serde_json::to_string_pretty(&evaluation_errors).expect("Failed to serialize errors");
+        if let Err(e) = fs::write(errors_path, errors) {
+            warn!("Failed to write errors to {errors_path:?} due to {e}");
+        }
+    }
+    // Embedded and sidecar rating of the files selected by --find-conflicts
+    let mut conflicts: HashMap<PathBuf, RatingConflict> = HashMap::new();
+    if cli.find_conflicts {
+        let found: Vec<Option<RatingConflict>> = pool.install(|| {
+            selected_paths
+                .par_iter()
+                .map(|(path, _)| {
+                    find_rating_conflict(path.path.clone(), &cli.rating_tag, cli.xmp_search())
+                        .inspect_err(|e| warn!("Skipping {:?} due to {e}", path.path))
+                        .ok()
+                        .flatten()
+                })
+                .collect()
+        });
+        let selected_count = selected_paths.len();
+        selected_paths = selected_paths
+            .into_iter()
+            .zip(found)
+            .filter_map(|((path, meta), conflict)| {
+                conflicts.insert(path.path.clone(), conflict?);
+                Some((path, meta))
+            })
+            .collect();
+        summary.skipped_without_conflict = Some(selected_count - selected_paths.len());
+    }
+
+    summary.passed_filter = selected_paths.len();
+    for (_, meta) in &selected_paths {
+        match meta.rating {
+            Some(rating) => *summary.ratings.entry(rating).or_default() += 1,
+            None => summary.unrated += 1,
+        }
+    }
+    let mut selected_paths = drop_top_rated_and_duplicates(
+        selected_paths,
+        cli.top_n_per_dir,
+        cli.dedup,
+        &pool,
+        &mut summary,
+    );
+    if let Some(sort) = &cli.sort {
+        sort_entries(&mut selected_paths, sort, cli.reverse);
+    }
+    if let Some(skip) = cli.skip {
+        summary.skipped_offset = selected_paths.len().min(skip);
+        selected_paths.drain(..summary.skipped_offset);
+    }
+    if let Some(limit) = cli.limit {
+        summary.skipped_limit = selected_paths.len().saturating_sub(limit);
+        selected_paths.truncate(limit);
+    }
+    if raw_command {
+        let with_raws = selected_paths
+            .iter()
+            .filter(|(entry, _)| !entry.raw_paths.is_empty())
+            .count();
+        summary.with_raws = Some(with_raws);
+    }
+
+    if cli.orphan_raws && cli.command != FileCommand::Print && !selected_paths.is_empty() {
+        warn!(
+            "{command_name} {} orphan raw files, none of them has an image left next to it",
+            selected_paths.len()
+        );
+    }
+
+    let print_json = cli.command == FileCommand::Print && cli.format == OutputFormat::Json;
+    let dest_dirs = DestinationDirs::default();
+    let mut prompt = cli.interactive
+        && [
+            FileCommand::Move,
+            FileCommand::Delete,
+            FileCommand::DeleteRaws,
+            FileCommand::Rename,
+        ]
+        .contains(&cli.command);
+    let mut print_records: Vec<PrintRecord> = Vec::new();
+    // Position of the file for the {seq} field of --name-template
+    let mut seq = 0;
+
+    for (path, meta) in selected_paths {
+        let rating = meta.rating;
+        let relative_path = path
+            .path
+            .strip_prefix(search_path.clone())
+            .expect(format!("Failed to strip root prefix of file {:?}", path).as_str());
 
-        let mut should_move = pass_treshold_check
-            && pass_label_check
-            && pass_ignore_label_check
-            && pass_include_label_check;
+        debug!("Rated: {} {command_name} {path}", rating_text(rating));
+
+        let conflict = conflicts.get(&path.path).copied();
+        if print_json {
+            print_records.push(PrintRecord {
+                path: printed_path(&path.path, cli.posix_paths).into(),
+                raw_paths: path
+                    .raw_paths
+                    .iter()
+                    .map(|raw_path| printed_path(raw_path, cli.posix_paths).into())
+                    .collect(),
+                rating,
+                label: meta.label,
+                capture_date: meta.capture_date,
+                conflict,
+                orphan_raw: cli.orphan_raws,
+            });
+            summary.acted += 1;
+            continue;
+        }
 
-        if cli.inverse {
-            should_move = !should_move;
+        // Tab separated columns printed after the path
+        let mut columns: Vec<String> = Vec::new();
+        if cli.show_date {
+            columns.push(match meta.capture_date {
+                Some(date) => date.format("%Y-%m-%d %H:%M:%S").to_string(),
+                None => "-".to_string(),
+            });
+        }
+        if let Some(conflict) = conflict {
+            columns.push(format!("embedded {}", conflict.embedded));
+            columns.push(format!("sidecar {}", conflict.sidecar));
         }
+        if cli.orphan_raws {
+            columns.push("orphan raw".to_string());
+        }
+        if cli.command == FileCommand::Print {
+            let mut line = printed_path(&path.path, cli.posix_paths);
+            for column in columns {
+                line.push('\t');
+                line.push_str(&column);
+            }
+            println!("{line}");
+            for raw_path in &path.raw_paths {
+                println!("{}", printed_path(raw_path, cli.posix_paths));
+            }
+            summary.acted += 1;
+            continue;
+        }
+
+        let route_dir = match &cli.route {
+            Some(routes) => match routes.destination(&meta, cli.treat_missing_as) {
+                Some(route_dir) => Some(route_dir),
+                None => {
+                    debug!("Skipping {path:?} as it matches no route");
+                    *summary.skipped_unrouted.get_or_insert(0) += 1;
+                    continue;
+                }
+            },
+            None => None,
+        };
 
-        if should_move {
-            if cli.verbose {
-                eprintln!("Rated: {rating} {command_name} {path}");
+        if prompt {
+            match confirm(command_name, &path, rating).context("Failed to read confirmation")? {
+                Confirmation::Yes => {}
+                Confirmation::No => {
+                    summary.declined += 1;
+                    continue;
+                }
+                Confirmation::All => prompt = false,
+                Confirmation::Quit => break,
             }
+        }
 
-            let mut dest_dir: Option<PathBuf> = None;
-            if requires_destination {
-                let Some(output_path) = output_path.clone() else {
-                    panic!("Did not specify destination path");
+        let mut dest_dir: Option<PathBuf> = None;
+        if uses_destination {
+            let Some(mut output_path) = output_path.clone() else {
+                panic!("Did not specify destination path");
+            };
+            if let Some(route_dir) = route_dir {
+                output_path.push(route_dir);
+            }
+            if cli.flatten && !uses_trash {
+                if let Err(e) = dest_dirs.create(&output_path, cli.dry_run) {
+                    error!("Failed to process {path:?} due to {e}");
+                    summary.errored += 1;
+                    continue;
+                }
+                dest_dir = Some(output_path);
+            } else if cli.organize_by_date && !uses_trash {
+                let date = match meta.capture_date {
+                    Some(date) => Ok(date),
+                    None => get_modified_date(&path.path),
+                };
+                let dir_path = match date {
+                    Ok(date) => output_path
+                        .join(date.format("%Y").to_string())
+                        .join(date.format("%Y-%m-%d").to_string()),
+                    Err(e) => {
+                        error!("Failed to process {path:?} due to {e}");
+                        summary.errored += 1;
+                        continue;
+                    }
                 };
+                if let Err(e) = dest_dirs.create(&dir_path, cli.dry_run) {
+                    error!("Failed to process {path:?} due to {e}");
+                    summary.errored += 1;
+                    continue;
+                }
+                dest_dir = Some(dir_path);
+            } else {
                 let new_file_path = output_path.join(&relative_path);
                 let dir_path: &Path = new_file_path.parent().unwrap();
-                if !path_exists(dir_path.to_path_buf()) {
-                    eprintln!("Creating destination directory: {dir_path:?}");
-                    fs::create_dir(dir_path.to_path_buf()).unwrap();
+                if let Err(e) = dest_dirs.create(dir_path, cli.dry_run) {
+                    error!("Failed to process {path:?} due to {e}");
+                    summary.errored += 1;
+                    continue;
                 }
                 dest_dir = Some(dir_path.to_path_buf());
             }
-
-            apply_command(
-                &cli.command,
-                cli.verbose,
-                path.clone(),
-                dest_dir,
-                cli.dry_run,
-                cli.r#override,
-            );
         }
-    }
-}
 
-fn apply_command(
-    command: &FileCommand,
-    verbose: bool,
-    path: Entry,
-    destination_directory: Option<PathBuf>,
-    dry_run: bool,
-    override_file: bool,
-) {
-    match command {
-        FileCommand::Move => {
-            let new_file_path = destination_directory
-                .clone()
-                .unwrap()
-                .join(path.path.file_name().unwrap());
-            move_file(path.path, new_file_path, dry_run, override_file, verbose);
-            if let Some(raw_path) = path.raw_path {
-                let new_file_path = destination_directory
-                    .unwrap()
-                    .join(raw_path.file_name().unwrap());
-                move_file(raw_path, new_file_path, dry_run, override_file, verbose);
-            }
-        }
-        FileCommand::Copy => {
-            let new_file_path = destination_directory
-                .clone()
-                .unwrap()
-                .join(path.path.file_name().unwrap());
-            copy_file(path.path, new_file_path, dry_run, override_file, verbose);
-            if let Some(raw_path) = path.raw_path {
-                let new_file_path = destination_directory
-                    .unwrap()
-                    .join(raw_path.file_name().unwrap());
-                copy_file(raw_path, new_file_path, dry_run, override_file, verbose);
-            }
-        }
-        FileCommand::Delete => {
-            remove_file(path.path, dry_run, verbose);
-            if let Some(raw_path) = path.raw_path {
-                remove_file(raw_path, dry_run, verbose);
+        let target_rating = match &cli.label_to_rating {
+            Some(label_ratings) if cli.command == FileCommand::SetRating => {
+                let Some(target_rating) = label_ratings.rating_for(meta.label.as_deref()) else {
+                    debug!(
+                        "Skipping {path:?} as its label {:?} isn't mapped",
+                        meta.label
+                    );
+                    *summary.skipped_unmapped_label.get_or_insert(0) += 1;
+                    continue;
+                };
+                Some(target_rating)
             }
-        }
-        FileCommand::Print => {
-            println!("{}", path.path.as_os_str().to_str().unwrap());
-            if let Some(raw_path) = path.raw_path {
-                println!("{}", raw_path.as_os_str().to_str().unwrap());
+            _ => cli.set_rating,
+        };
+
+        let new_stem = match &cli.name_template {
+            Some(name_template) if cli.command == FileCommand::Rename => {
+                seq += 1;
+                match name_template.render(&path.path, &meta, seq) {
+                    Ok(new_stem) => Some(new_stem),
+                    Err(e) => {
+                        error!("Failed to process {path:?} due to {e}");
+                        summary.errored += 1;
+                        continue;
+                    }
+                }
             }
+            _ => None,
+        };
+
+        if let Some(manifest) = ctx.manifest.as_deref_mut() {
+            manifest.start_entry(&meta);
         }
-        FileCommand::DeleteRaws => {
-            if let Some(raw_path) = path.raw_path {
-                remove_file(raw_path, dry_run, verbose);
+        let targets = CommandTargets {
+            rating: target_rating,
+            label: cli.set_label.as_deref(),
+            stem: new_stem.as_deref(),
+        };
+        let res = apply_command(
+            &cli.command,
+            path.clone(),
+            dest_dir.clone(),
+            &meta,
+            targets,
+            &rating_options,
+            &mut ctx,
+        );
+        match res {
+            Ok(Outcome::Applied) => {
+                summary.acted += 1;
+                if let (Some(destinations), Some(dest_dir)) =
+                    (summary.destinations.as_mut(), dest_dir)
+                {
+                    let total = destinations.entry(dest_dir).or_default();
+                    for file in placed_files(&cli.command, &path) {
+                        total.files += 1;
+                        total.bytes += fs::metadata(file).map_or(0, |metadata| metadata.len());
+                    }
+                }
             }
-        }
-        FileCommand::CopyRaws => {
-            if let Some(raw_path) = path.raw_path {
-                let new_file_path = destination_directory
-                    .unwrap()
-                    .join(raw_path.file_name().unwrap());
-                copy_file(raw_path, new_file_path, dry_run, override_file, verbose);
+            Ok(Outcome::SkippedExisting) => summary.skipped_existing += 1,
+            Err(e) => {
+                error!("Failed to process {path:?} due to {e}");
+                summary.errored += 1;
+                if let Some(failed_verification) = summary.failed_verification.as_mut() {
+                    if e.downcast_ref::<ChecksumMismatch>().is_some() {
+                        *failed_verification += 1;
+                    }
+                }
             }
         }
-        FileCommand::CopyRatingToRaws => {
-            if let Some(raw_path) = path.raw_path {
-                copy_rating(path.path, raw_path, dry_run, override_file, verbose);
-            }
+    }
+
+    if print_json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&print_records).expect("Failed to serialize output")
+        );
+    }
+    if !cli.quiet {
+        eprintln!("{command_name} summary\n{summary}");
+    }
+
+    if let Some(report_path) = cli.report {
+        let report = serde_json::to_string_pretty(&summary).expect("Failed to serialize report");
+        if let Err(e) = fs::write(&report_path, report) {
+            warn!("Failed to write report to {report_path:?} due to {e}");
         }
     }
+
+    Ok(exit_code(summary.errored))
 }
 
-fn remove_file<P: AsRef<Path>>(path: P, dry_run: bool, verbose: bool) {
-    if verbose {
-        eprintln!("rm {:?}", path.as_ref());
-    }
-    match dry_run {
-        true => println!("rm {:?}", path.as_ref()),
-        false => fs::remove_file(path).unwrap(),
+fn exit_code(failed_count: usize) -> ExitCode {
+    match failed_count {
+        0 => ExitCode::SUCCESS,
+        _ => ExitCode::from(EXIT_FILE_ERRORS),
     }
 }
 
-fn move_file<P: AsRef<Path>>(path: P, dest: P, dry_run: bool, override_file: bool, verbose: bool) {
-    if dest.as_ref().exists() {
-        if !override_file {
-            if verbose {
-                eprintln!(
-                    "Skipping {:?} as {:?} it already exists",
-                    path.as_ref(),
-                    dest.as_ref()
-                );
+/// Adds the frames of every burst with a selected frame, after the selected frames
+fn add_burst_frames(
+    selected: &mut Vec<(Entry, FileMeta)>,
+    mut bursts: HashMap<PathBuf, Vec<Entry>>,
+    mut read_metadata: impl FnMut(&Entry) -> Option<FileMeta>,
+) {
+    let selected_paths: HashSet<PathBuf> = selected
+        .iter()
+        .map(|(entry, _)| entry.path.clone())
+        .collect();
+    let selected_bursts: Vec<PathBuf> = selected
+        .iter()
+        .filter_map(|(entry, _)| entry.burst.clone())
+        .collect();
+    for burst in selected_bursts {
+        for frame in bursts.remove(&burst).unwrap_or_default() {
+            if selected_paths.contains(&frame.path) {
+                continue;
             }
-            return;
-        } else {
-            if verbose {
-                eprintln!(
-                    "Overriding existing {:?} with {:?}",
-                    dest.as_ref(),
-                    path.as_ref()
-                );
+            debug!("Adding frame {:?} of burst {:?}", frame.path, burst);
+            if let Some(meta) = read_metadata(&frame) {
+                selected.push((frame, meta));
             }
         }
     }
-    if verbose {
-        eprintln!("mv {:?} {:?}", path.as_ref(), dest.as_ref());
-    }
-    match dry_run {
-        true => println!("mv {:?} {:?}", path.as_ref(), dest.as_ref()),
-        false => fs::rename(path, dest).unwrap(),
-    }
 }
 
-fn copy_file<P: AsRef<Path>>(path: P, dest: P, dry_run: bool, override_file: bool, verbose: bool) {
-    if dest.as_ref().exists() {
-        if !override_file {
-            if verbose {
-                eprintln!(
-                    "Skipping {:?} as {:?} it already exists",
-                    path.as_ref(),
-                    dest.as_ref()
-                );
-            }
-            return;
-        } else {
-            if verbose {
-                eprintln!(
-                    "Overriding existing {:?} with {:?}",
-                    dest.as_ref(),
-                    path.as_ref()
+/// Leaves out the `top_n` highest rated files of each directory, ranking equal ratings by
+/// file name so repeated runs keep the same files
+fn drop_top_rated_per_dir(
+    selected: Vec<(Entry, FileMeta)>,
+    top_n: usize,
+) -> Vec<(Entry, FileMeta)> {
+    let mut by_dir: BTreeMap<PathBuf, Vec<(Entry, FileMeta)>> = BTreeMap::new();
+    for (entry, meta) in selected {
+        let dir = entry.path.parent().unwrap_or(Path::new("")).to_path_buf();
+        by_dir.entry(dir).or_default().push((entry, meta));
+    }
+    by_dir
+        .into_values()
+        .flat_map(|mut entries| {
+            entries.sort_by(|(a, a_meta), (b, b_meta)| {
+                b_meta
+                    .rating
+                    .cmp(&a_meta.rating)
+                    .then_with(|| a.path.file_name().cmp(&b.path.file_name()))
+            });
+            for (entry, meta) in entries.iter().take(top_n) {
+                info!(
+                    "Keeping {:?} as one of the top rated in its directory ({})",
+                    entry.path,
+                    rating_text(meta.rating)
                 );
             }
-        }
-    }
-    if verbose {
-        eprintln!("cp {:?} {:?}", path.as_ref(), dest.as_ref());
+            entries.into_iter().skip(top_n)
+        })
+        .collect()
+}
+
+/// Applies --top-n-per-dir and --dedup, counting the files each leaves out
+fn drop_top_rated_and_duplicates(
+    mut selected: Vec<(Entry, FileMeta)>,
+    top_n_per_dir: Option<usize>,
+    dedup: bool,
+    pool: &ThreadPool,
+    summary: &mut RunSummary,
+) -> Vec<(Entry, FileMeta)> {
+    if let Some(top_n) = top_n_per_dir {
+        let selected_count = selected.len();
+        selected = drop_top_rated_per_dir(selected, top_n);
+        summary.kept_top_rated = Some(selected_count - selected.len());
     }
-    match dry_run {
-        true => {
-            println!("cp {:?} {:?}", path.as_ref(), dest.as_ref());
-        }
-        false => {
-            fs::copy(path, dest).unwrap();
-        }
+    if dedup {
+        let selected_count = selected.len();
+        selected = dedup_entries(selected, pool);
+        summary.skipped_duplicate = selected_count - selected.len();
     }
+    selected
 }
 
-fn copy_rating(path: PathBuf, dest: PathBuf, dry_run: bool, override_file: bool, verbose: bool) {
-    let rating = match get_rating(path.clone()) {
-        Ok(rating) => rating,
-        Err(_) => {
-            if !override_file {
-                if verbose {
-                    eprintln!("Skipping {:?} as {:?} does not have rating", path, dest);
-                }
-                return;
-            } else {
-                if verbose {
-                    eprintln!("Removing rating from {:?}", dest);
-                }
-                0
+/// Keeps the highest rated selected frame of each burst, the first one on ties
+fn keep_best_per_burst(selected: Vec<(Entry, FileMeta)>) -> Vec<(Entry, FileMeta)> {
+    let mut best: HashMap<PathBuf, (&Path, Option<i32>)> = HashMap::new();
+    for (entry, meta) in &selected {
+        if let Some(burst) = &entry.burst {
+            let (best_path, best_rating) = best
+                .entry(burst.clone())
+                .or_insert((&entry.path, meta.rating));
+            if meta.rating > *best_rating {
+                *best_path = &entry.path;
+                *best_rating = meta.rating;
             }
         }
-    };
-    if verbose {
-        eprintln!("cp rating: {} {:?} -> {:?}", rating, path, dest);
     }
-    match dry_run {
-        true => {
-            println!("cp rating: {} {:?} -> {:?}", rating, path, dest);
-        }
-        false => {
-            set_rating(dest, rating).unwrap();
-        }
+    let best_paths: HashSet<PathBuf> = best
+        .into_values()
+        .map(|(path, _)| path.to_path_buf())
+        .collect();
+    selected
+        .into_iter()
+        .filter(|(entry, _)| {
+            let keep = entry.burst.is_none() || best_paths.contains(&entry.path);
+            if !keep {
+                info!(
+                    "Skipping {:?} as a better frame of its burst is selected",
+                    entry.path
+                );
+            }
+            keep
+        })
+        .collect()
+}
+
+/// Path as printed by the print command, without the quotes of its debug format. With
+/// `posix` Windows separators are replaced by forward slashes
+fn printed_path(path: &Path, posix: bool) -> String {
+    let path = path.to_string_lossy();
+    // Backslashes are valid in file names elsewhere
+    match posix && std::path::MAIN_SEPARATOR == '\\' {
+        true => path.replace('\\', "/"),
+        false => path.into_owned(),
     }
 }
 
-fn visit_dirs(
-    dir: &Path,
-    paths: &mut Vec<Entry>,
-    depth: i32,
-    excluded_paths: Vec<String>,
-    flip_exclusion: bool,
-    include_videos: bool,
-    raws_matched: bool,
-    raw_path: Option<&PathBuf>,
-    search_dir: &Path,
-    verbose: bool,
-) -> io::Result<()> {
-    if dir.is_dir() {
-        for entry in fs::read_dir(dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_dir() {
-                let dir_name = path
-                    .as_path()
-                    .file_name()
-                    .expect("Could not get relative path")
-                    .to_str()
-                    .unwrap();
-                let mut filter_res = filter_string(dir_name, excluded_paths.clone());
-                if flip_exclusion {
-                    filter_res = !filter_res;
-                }
-                if (depth != 0 || filter_res) && !dir_name.starts_with(".") {
-                    // filter
-                    if verbose && depth == 0 {
-                        eprintln!("Including {dir_name}");
-                    }
-                    visit_dirs(
-                        &path,
-                        paths,
-                        depth + 1,
-                        excluded_paths.clone(),
-                        flip_exclusion,
-                        include_videos,
-                        raws_matched,
-                        raw_path,
-                        search_dir,
-                        verbose,
-                    )?;
-                }
-            } else {
-                let path_buf = entry.path();
-                if is_file_allowed(&path_buf, include_videos) {
-                    let raw_file_path = match raw_path {
-                        Some(raw_base_path) => {
-                            // Calculate new relative path within raw directory
-                            let relative_path = path_buf
-                                .strip_prefix(search_dir)
-                                .expect(
-                                    format!("Failed to strip root prefix of file {:?}", path)
-                                        .as_str(),
-                                );
-
-                            let new_file_path = raw_base_path.join(&relative_path);
-                            let dir_path: &Path = new_file_path.parent().unwrap();
-
-                            let mut file_stem = path_buf
-                                .file_stem()
-                                .unwrap()
-                                .to_string_lossy()
-                                .trim_suffix("_c")
-                                .to_string();
-
-                            file_stem.push_str(".ARW");
-                            dir_path.join(file_stem)
-                        }
-                        None => path_buf.with_extension("ARW"),
-                    };
-                    if raws_matched && raw_file_path.exists() {
-                        if verbose {
-                            eprintln!("Matched raw file {raw_file_path:?}");
-                        }
-                        paths.push(Entry::new_with_raw(path_buf, raw_file_path));
-                    } else {
-                        paths.push(Entry::new(path_buf));
-                    }
-                } else {
-                    if verbose {
-                        eprintln!("Skipping file {path_buf:?}");
-                    }
-                }
-            }
-        }
+/// Files of the entry the command places in the destination directory, leaving out sidecars
+fn placed_files<'a>(command: &FileCommand, entry: &'a Entry) -> Vec<&'a PathBuf> {
+    match command {
+        FileCommand::CopyRaws | FileCommand::DeleteRaws => entry.raw_paths.iter().collect(),
+        _ => std::iter::once(&entry.path)
+            .chain(&entry.raw_paths)
+            .collect(),
     }
-    Ok(())
 }
 
-fn filter_string(string: &str, excluded_paths: Vec<String>) -> bool {
-    for path in excluded_paths {
-        if string.contains(&path) {
-            return false;
-        }
+/// Formats a byte count with binary units, e.g. `1.5 GiB`
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
     }
-    true
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
 }
 
-fn path_exists(path: PathBuf) -> bool {
-    fs::metadata(path).is_ok()
+fn dedup_entries(entries: Vec<(Entry, FileMeta)>, pool: &ThreadPool) -> Vec<(Entry, FileMeta)> {
+    // Raws are tied to their primary image, so only the primary file is hashed
+    let hashes: Vec<Option<blake3::Hash>> = pool.install(|| {
+        entries
+            .par_iter()
+            .map(|(path, _)| match hash_file(&path.path) {
+                Ok(hash) => Some(hash),
+                Err(e) => {
+                    warn!("Unable to hash {:?} due to {e}", path.path);
+                    None
+                }
+            })
+            .collect()
+    });
+
+    let mut seen_hashes: HashMap<blake3::Hash, PathBuf> = HashMap::new();
+    entries
+        .into_iter()
+        .zip(hashes)
+        .filter(|((path, _), hash)| {
+            let Some(hash) = hash else {
+                return true;
+            };
+            match seen_hashes.get(hash) {
+                Some(original_path) => {
+                    info!("Skipping duplicate {:?} of {:?}", path.path, original_path);
+                    false
+                }
+                None => {
+                    seen_hashes.insert(*hash, path.path.clone());
+                    true
+                }
+            }
+        })
+        .map(|(entry, _)| entry)
+        .collect()
 }
 
-fn get_rating(filename: PathBuf) -> Result<i32> {
-    if !path_exists(filename.clone()) {
-        anyhow::bail!("File doesn't exist");
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(path: &str, burst: Option<&str>, rating: Option<i32>) -> (Entry, FileMeta) {
+        let mut entry = Entry::new(PathBuf::from(path));
+        entry.burst = burst.map(PathBuf::from);
+        let meta = FileMeta {
+            rating,
+            ..FileMeta::default()
+        };
+        (entry, meta)
     }
 
-    // Use xmp-toolkit for video files
-    if is_video(&filename) {
-        return Ok(read_rating_xmp(filename.clone()).unwrap_or(0));
+    fn paths(selected: &[(Entry, FileMeta)]) -> Vec<&Path> {
+        selected
+            .iter()
+            .map(|(entry, _)| entry.path.as_path())
+            .collect()
     }
 
-    // Use rexiv2 for image files
-    let meta = Metadata::new_from_path(filename);
-    match meta {
-        Ok(meta) => {
-            let rating = meta.get_tag_numeric("Xmp.xmp.Rating");
-            Ok(rating)
+    #[test]
+    fn add_burst_frames_adds_frames_of_selected_bursts() {
+        let mut selected = vec![
+            frame("a_001.jpg", Some("a"), Some(5)),
+            frame("single.jpg", None, Some(5)),
+        ];
+        let mut bursts: HashMap<PathBuf, Vec<Entry>> = HashMap::new();
+        for (path, burst) in [
+            ("a_001.jpg", "a"),
+            ("a_002.jpg", "a"),
+            ("a_003.jpg", "a"),
+            ("b_001.jpg", "b"),
+            ("b_002.jpg", "b"),
+        ] {
+            bursts
+                .entry(PathBuf::from(burst))
+                .or_default()
+                .push(frame(path, Some(burst), None).0);
         }
-        Err(e) => anyhow::bail!(e),
+        // Frames whose metadata can't be read are left out
+        add_burst_frames(&mut selected, bursts, |entry| {
+            (entry.path != Path::new("a_003.jpg")).then(FileMeta::default)
+        });
+        assert_eq!(
+            paths(&selected),
+            vec![
+                Path::new("a_001.jpg"),
+                Path::new("single.jpg"),
+                Path::new("a_002.jpg")
+            ]
+        );
     }
-}
 
-fn set_rating(path: PathBuf, rating: i32) -> Result<(), ExifToolError> {
-    let mut exiftool = ExifTool::new().unwrap();
-    exiftool.write_tag(path.as_path(), "Rating", &rating, &["-overwrite_original"])
-}
-
-fn is_video(path: &Path) -> bool {
-    let extension = path
-        .extension()
-        .unwrap_or_default()
-        .to_str()
-        .unwrap_or("")
-        .to_lowercase();
-    VIDEOS_EXTENSIONS.contains(&extension.as_str())
-}
+    #[test]
+    fn keep_best_per_burst_keeps_highest_rated_frame() {
+        let selected = vec![
+            frame("a_001.jpg", Some("a"), Some(3)),
+            frame("a_002.jpg", Some("a"), Some(5)),
+            frame("a_003.jpg", Some("a"), Some(5)),
+            frame("b_001.jpg", Some("b"), None),
+            frame("b_002.jpg", Some("b"), None),
+            frame("single.jpg", None, Some(1)),
+        ];
+        assert_eq!(
+            paths(&keep_best_per_burst(selected)),
+            vec![
+                Path::new("a_002.jpg"),
+                Path::new("b_001.jpg"),
+                Path::new("single.jpg")
+            ]
+        );
+    }
 
-fn get_label(filename: PathBuf) -> Result<Option<String>, String> {
-    if !path_exists(filename.clone()) {
-        return Err("File doesn't exist".to_string());
+    #[test]
+    fn drop_top_rated_per_dir_ranks_each_directory() {
+        let selected = || {
+            vec![
+                frame("a/x.jpg", None, Some(3)),
+                frame("b/c.jpg", None, Some(1)),
+                frame("a/u.jpg", None, None),
+                frame("a/y.jpg", None, Some(5)),
+                frame("a/z.jpg", None, Some(0)),
+                frame("a/b.jpg", None, Some(3)),
+            ]
+        };
+        // Equal ratings are ranked by name and unrated files last
+        let dropped = drop_top_rated_per_dir(selected(), 2);
+        assert_eq!(
+            paths(&dropped),
+            ["a/x.jpg", "a/z.jpg", "a/u.jpg"].map(Path::new)
+        );
+        let dropped = drop_top_rated_per_dir(selected(), 0);
+        assert_eq!(
+            paths(&dropped),
+            ["a/y.jpg", "a/b.jpg", "a/x.jpg", "a/z.jpg", "a/u.jpg", "b/c.jpg"].map(Path::new)
+        );
+        assert!(drop_top_rated_per_dir(selected(), 10).is_empty());
     }
 
-    let meta = Metadata::new_from_path(filename);
-    match meta {
-        Ok(meta) => {
-            let label = meta.get_tag_string("Xmp.xmp.Label");
-            match label {
-                Ok(label) => Ok(Some(label)),
-                Err(_) => Ok(None),
-            }
-        }
-        Err(e) => Err(e.to_string()),
+    #[test]
+    fn sort_entries_by_key() {
+        let entry = |path, rating, day: Option<u32>| {
+            let (entry, mut meta) = frame(path, None, rating);
+            meta.capture_date = day.map(|day| {
+                NaiveDate::from_ymd_opt(2024, 7, day)
+                    .unwrap()
+                    .and_hms_opt(12, 0, 0)
+                    .unwrap()
+            });
+            (entry, meta)
+        };
+        let sorted = |sort, reverse| {
+            let mut entries = vec![
+                entry("b.jpg", Some(3), Some(2)),
+                entry("a.jpg", Some(5), None),
+                entry("c.jpg", None, Some(1)),
+                entry("d.jpg", Some(3), Some(3)),
+            ];
+            sort_entries(&mut entries, &sort, reverse);
+            entries
+        };
+        assert_eq!(
+            paths(&sorted(SortKey::Path, false)),
+            ["a.jpg", "b.jpg", "c.jpg", "d.jpg"].map(Path::new)
+        );
+        assert_eq!(
+            paths(&sorted(SortKey::Path, true)),
+            ["d.jpg", "c.jpg", "b.jpg", "a.jpg"].map(Path::new)
+        );
+        // Equal ratings keep their order, unrated files and undated files come last
+        assert_eq!(
+            paths(&sorted(SortKey::Rating, false)),
+            ["a.jpg", "b.jpg", "d.jpg", "c.jpg"].map(Path::new)
+        );
+        assert_eq!(
+            paths(&sorted(SortKey::Rating, true)),
+            ["c.jpg", "d.jpg", "b.jpg", "a.jpg"].map(Path::new)
+        );
+        assert_eq!(
+            paths(&sorted(SortKey::Date, false)),
+            ["c.jpg", "b.jpg", "d.jpg", "a.jpg"].map(Path::new)
+        );
     }
-}
 
-fn get_tags(filename: PathBuf) -> Result<Option<Vec<String>>, String> {
-    if !path_exists(filename.clone()) {
-        return Err("File doesn't exist".to_string());
+    #[test]
+    fn top_rated_files_are_not_counted_as_duplicates() {
+        let pool = rayon::ThreadPoolBuilder::new().build().unwrap();
+        let selected = vec![
+            frame("a/x.jpg", None, Some(5)),
+            frame("a/y.jpg", None, Some(3)),
+            frame("b/z.jpg", None, Some(4)),
+        ];
+        let mut summary = RunSummary::default();
+        let selected = drop_top_rated_and_duplicates(selected, Some(1), false, &pool, &mut summary);
+        assert_eq!(paths(&selected), [Path::new("a/y.jpg")]);
+        assert_eq!(summary.kept_top_rated, Some(2));
+        assert_eq!(summary.skipped_duplicate, 0);
     }
 
-    let meta = Metadata::new_from_path(filename);
-    match meta {
-        Ok(meta) => {
-            let tags = meta.get_tag_multiple_strings("Xmp.digiKam.TagsList");
-            match tags {
-                Ok(tags) => Ok(Some(tags.iter().map(|t| t.to_lowercase()).collect())),
-                Err(_) => Ok(None),
-            }
-        }
-        Err(e) => Err(e.to_string()),
+    fn parse(args: &[&str], config_file: ConfigFile) -> Result<Cli> {
+        config_file.parse(&CommandLine::parse(args.iter().map(OsString::from))?)
     }
-}
 
-fn is_file_allowed(filename: &PathBuf, include_videos: bool) -> bool {
-    if filename
-        .file_name()
-        .unwrap()
-        .to_string_lossy()
-        .starts_with(".")
-    {
-        return false;
+    #[test]
+    fn config_file_options_yield_to_command_line() {
+        let config_file = || ConfigFile {
+            threshold: Some(3),
+            exclude: Some(vec!["a".to_string()]),
+            verbose: Some(Verbosity::Level(2)),
+            ..Default::default()
+        };
+        let cli = parse(&["exif", "print"], config_file()).unwrap();
+        assert_eq!(cli.threshold, 3);
+        assert_eq!(cli.exclude, ["a"]);
+        assert_eq!(cli.verbose, 2);
+
+        let args = ["exif", "-t", "4", "--exclude", "b", "-v", "print"];
+        let cli = parse(&args, config_file()).unwrap();
+        assert_eq!(cli.threshold, 4);
+        assert_eq!(cli.exclude, ["b"]);
+        assert_eq!(cli.verbose, 1);
     }
 
-    let ext = filename
-        .extension()
-        .unwrap_or(OsStr::new(""))
-        .to_str()
-        .unwrap();
-    let lower_passed = ext.to_lowercase();
+    #[test]
+    fn config_file_options_are_validated() {
+        let orphan_raws = || ConfigFile {
+            orphan_raws: Some(true),
+            ..Default::default()
+        };
+        assert!(parse(&["exif", "--raw-src", "raws", "print"], orphan_raws()).is_err());
+        let flat_raw_src = ConfigFile {
+            flat_raw_src: Some(true),
+            ..Default::default()
+        };
+        assert!(parse(&["exif", "print"], flat_raw_src).is_err());
+        let threshold = ConfigFile {
+            threshold: Some(6),
+            ..Default::default()
+        };
+        assert!(parse(&["exif", "print"], threshold).is_err());
+        let format = ConfigFile {
+            format: Some("yaml".to_string()),
+            ..Default::default()
+        };
+        assert!(parse(&["exif", "print"], format).is_err());
 
-    let mut ext: Vec<&str> = IMAGE_EXTENSIONS.to_vec();
+        // A flag turned off in the config file doesn't conflict
+        let no_orphan_raws = ConfigFile {
+            orphan_raws: Some(false),
+            ..Default::default()
+        };
+        assert!(parse(&["exif", "--raw-src", "raws", "print"], no_orphan_raws).is_ok());
+    }
 
-    if include_videos {
-        ext.extend(VIDEOS_EXTENSIONS.iter());
+    #[test]
+    fn config_file_flags_turn_off_from_command_line() {
+        let config_file = || ConfigFile {
+            orphan_raws: Some(true),
+            inverse: Some(true),
+            ..Default::default()
+        };
+        let cli = parse(&["exif", "print"], config_file()).unwrap();
+        assert!(cli.orphan_raws && cli.inverse);
+
+        let args = [
+            "exif",
+            "--orphan-raws=false",
+            "-i=false",
+            "--raw-src",
+            "raws",
+            "print",
+        ];
+        let cli = parse(&args, config_file()).unwrap();
+        assert!(!cli.orphan_raws && !cli.inverse);
+        assert_eq!(cli.raw_src, Some(PathBuf::from("raws")));
     }
 
-    for allowed_extension in ext {
-        let lower_allowed = allowed_extension.to_lowercase();
-        if lower_allowed == lower_passed {
-            return true;
-        }
+    #[test]
+    fn config_file_verbose_is_a_flag_or_level() {
+        let verbosity = |value: serde_json::Value| Verbosity::deserialize(value).unwrap();
+        assert_eq!(verbosity(true.into()).level(), 1);
+        assert_eq!(verbosity(false.into()).level(), 0);
+        assert_eq!(verbosity(2.into()).level(), 2);
     }
-    false
 }
@@ -0,0 +1,139 @@
+use crate::FileMeta;
+use anyhow::Result;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+
+const HEADER: [&str; 7] = [
+    "operation",
+    "source",
+    "destination",
+    "rating",
+    "label",
+    "capture_date",
+    "dry_run",
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ManifestOp {
+    Move,
+    Copy,
+    Delete,
+    SetRating,
+    SetLabel,
+    StripGps,
+    CopyRating,
+}
+
+impl Display for ManifestOp {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ManifestOp::Move => write!(f, "move"),
+            ManifestOp::Copy => write!(f, "copy"),
+            ManifestOp::Delete => write!(f, "delete"),
+            ManifestOp::SetRating => write!(f, "set-rating"),
+            ManifestOp::SetLabel => write!(f, "set-label"),
+            ManifestOp::StripGps => write!(f, "strip-gps"),
+            ManifestOp::CopyRating => write!(f, "copy-rating"),
+        }
+    }
+}
+
+/// CSV listing of the moved, copied and deleted files and of those whose metadata was
+/// written, dry runs list what would happen. Raws and sidecars get rows of their own with
+/// the metadata of their entry
+pub struct Manifest {
+    writer: csv::Writer<File>,
+    entry_meta: FileMeta,
+}
+
+impl Manifest {
+    /// Opens the manifest for appending, the header is only written to a new file
+    pub fn open(path: &Path) -> Result<Manifest> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let is_new = file.metadata()?.len() == 0;
+        let mut writer = csv::Writer::from_writer(file);
+        if is_new {
+            writer.write_record(HEADER)?;
+            writer.flush()?;
+        }
+        Ok(Manifest {
+            writer,
+            entry_meta: FileMeta::default(),
+        })
+    }
+
+    /// Sets the metadata written with the rows of the next entry
+    pub fn start_entry(&mut self, meta: &FileMeta) {
+        self.entry_meta = meta.clone();
+    }
+
+    pub fn record(
+        &mut self,
+        op: ManifestOp,
+        source: &Path,
+        destination: Option<&Path>,
+        dry_run: bool,
+    ) -> Result<()> {
+        let meta = &self.entry_meta;
+        self.writer.write_record([
+            op.to_string(),
+            source.to_string_lossy().into_owned(),
+            destination
+                .map(|destination| destination.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            meta.rating
+                .map(|rating| rating.to_string())
+                .unwrap_or_default(),
+            meta.label.clone().unwrap_or_default(),
+            meta.capture_date
+                .map(|date| date.format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_default(),
+            dry_run.to_string(),
+        ])?;
+        // Flushed per row so an interrupted run still lists everything it did
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn manifest_appends_rows_after_one_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("manifest.csv");
+
+        let mut manifest = Manifest::open(&path).unwrap();
+        manifest.start_entry(&FileMeta {
+            rating: Some(4),
+            label: Some("Red, \"final\"".to_string()),
+            ..FileMeta::default()
+        });
+        manifest
+            .record(
+                ManifestOp::Move,
+                Path::new("a, b.jpg"),
+                Some(Path::new("dest/a, b.jpg")),
+                false,
+            )
+            .unwrap();
+        drop(manifest);
+
+        let mut manifest = Manifest::open(&path).unwrap();
+        manifest
+            .record(ManifestOp::SetRating, Path::new("c.jpg"), None, true)
+            .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "operation,source,destination,rating,label,capture_date,dry_run\n\
+             move,\"a, b.jpg\",\"dest/a, b.jpg\",4,\"Red, \"\"final\"\"\",,false\n\
+             set-rating,c.jpg,,,,,true\n"
+        );
+    }
+}
@@ -0,0 +1,246 @@
+use crate::stars_from_percent;
+use anyhow::Result;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Largest movie box read into memory, the media data is always skipped
+const MAX_MOOV_SIZE: u64 = 64 * 1024 * 1024;
+const USER_RATING_KEY: &[u8] = b"com.apple.quicktime.rating.user";
+const XTRA_RATING_NAME: &[u8] = b"WM/SharedUserRating";
+const DATA_TYPE_UTF8: u32 = 1;
+const DATA_TYPE_SIGNED: u32 = 21;
+const DATA_TYPE_UNSIGNED: u32 = 22;
+const DATA_TYPE_FLOAT32: u32 = 23;
+const DATA_TYPE_FLOAT64: u32 = 24;
+const XTRA_TYPE_INT64: u16 = 19;
+
+type Boxes<'a> = Vec<([u8; 4], &'a [u8])>;
+
+/// Reads a 0-5 star rating from the QuickTime `moov/meta` item list or the Microsoft
+/// `moov/udta/Xtra` box
+pub fn read_rating_quicktime(filename: &Path) -> Result<Option<i32>> {
+    let Some(moov) = read_moov(filename)? else {
+        return Ok(None);
+    };
+    let children = boxes(&moov);
+    let keys_rating = find_box(&children, b"meta").and_then(rating_from_meta);
+    let xtra_rating = find_box(&children, b"udta")
+        .and_then(|udta| find_box(&boxes(udta), b"Xtra"))
+        .and_then(rating_from_xtra);
+    Ok(keys_rating.or(xtra_rating))
+}
+
+/// Walks the top level boxes without reading them and returns the payload of `moov`
+fn read_moov(filename: &Path) -> Result<Option<Vec<u8>>> {
+    let mut file = File::open(filename)?;
+    let file_size = file.metadata()?.len();
+    let mut offset = 0;
+    while offset + 8 <= file_size {
+        file.seek(SeekFrom::Start(offset))?;
+        let mut header = [0u8; 8];
+        file.read_exact(&mut header)?;
+        let mut header_size = 8;
+        let size = match u32::from_be_bytes(header[..4].try_into()?) {
+            0 => file_size - offset,
+            1 => {
+                let mut large_size = [0u8; 8];
+                file.read_exact(&mut large_size)?;
+                header_size = 16;
+                u64::from_be_bytes(large_size)
+            }
+            size => size as u64,
+        };
+        if size < header_size {
+            anyhow::bail!("Invalid box size {size} at offset {offset}");
+        }
+        if &header[4..] == b"moov" {
+            let payload_size = size - header_size;
+            if payload_size > MAX_MOOV_SIZE {
+                anyhow::bail!("Movie box of {payload_size} bytes is too large");
+            }
+            let mut moov = vec![0; payload_size as usize];
+            file.read_exact(&mut moov)?;
+            return Ok(Some(moov));
+        }
+        offset += size;
+    }
+    Ok(None)
+}
+
+/// Splits a payload into its child boxes, stopping at the first malformed header
+fn boxes(data: &[u8]) -> Boxes<'_> {
+    let mut children = Vec::new();
+    let mut rest = data;
+    while rest.len() >= 8 {
+        let box_type: [u8; 4] = rest[4..8].try_into().unwrap();
+        let (header_size, size) = match u32::from_be_bytes(rest[..4].try_into().unwrap()) {
+            0 => (8, rest.len()),
+            1 if rest.len() >= 16 => (
+                16,
+                u64::from_be_bytes(rest[8..16].try_into().unwrap()) as usize,
+            ),
+            size => (8, size as usize),
+        };
+        if size < header_size || size > rest.len() {
+            break;
+        }
+        children.push((box_type, &rest[header_size..size]));
+        rest = &rest[size..];
+    }
+    children
+}
+
+fn find_box<'a>(children: &Boxes<'a>, box_type: &[u8; 4]) -> Option<&'a [u8]> {
+    children
+        .iter()
+        .find(|(child_type, _)| child_type == box_type)
+        .map(|(_, payload)| *payload)
+}
+
+fn rating_from_meta(meta: &[u8]) -> Option<i32> {
+    // QuickTime meta boxes start with the handler, ISO ones with a version and flags
+    let meta = match meta.get(4..8) {
+        Some(b"hdlr") => meta,
+        _ => meta.get(4..)?,
+    };
+    let children = boxes(meta);
+    let index = key_index(find_box(&children, b"keys")?, USER_RATING_KEY)?;
+    let item = boxes(find_box(&children, b"ilst")?)
+        .into_iter()
+        .find(|(item_type, _)| u32::from_be_bytes(*item_type) == index)?
+        .1;
+    let value = data_value(find_box(&boxes(item), b"data")?)?;
+    Some((value.round() as i32).clamp(0, 5))
+}
+
+/// Item list entries are named by the 1-based index of their key
+fn key_index(keys: &[u8], key: &[u8]) -> Option<u32> {
+    let count = u32::from_be_bytes(keys.get(4..8)?.try_into().ok()?);
+    let mut rest = keys.get(8..)?;
+    for index in 1..=count {
+        let size = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+        // Each key is its size, a namespace and the name
+        if rest.get(8..size)? == key {
+            return Some(index);
+        }
+        rest = rest.get(size..)?;
+    }
+    None
+}
+
+fn data_value(data: &[u8]) -> Option<f64> {
+    let data_type = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) & 0x00ff_ffff;
+    // The type is followed by a locale
+    let value = data.get(8..)?;
+    match data_type {
+        DATA_TYPE_UTF8 => std::str::from_utf8(value).ok()?.trim().parse().ok(),
+        DATA_TYPE_SIGNED => be_int(value, true),
+        DATA_TYPE_UNSIGNED => be_int(value, false),
+        DATA_TYPE_FLOAT32 => Some(f32::from_be_bytes(value.try_into().ok()?) as f64),
+        DATA_TYPE_FLOAT64 => Some(f64::from_be_bytes(value.try_into().ok()?)),
+        _ => None,
+    }
+}
+
+fn be_int(value: &[u8], signed: bool) -> Option<f64> {
+    if value.is_empty() || value.len() > 8 {
+        return None;
+    }
+    let fill = match signed && value[0] & 0x80 != 0 {
+        true => 0xff,
+        false => 0,
+    };
+    let mut bytes = [fill; 8];
+    bytes[8 - value.len()..].copy_from_slice(value);
+    Some(match signed {
+        true => i64::from_be_bytes(bytes) as f64,
+        false => u64::from_be_bytes(bytes) as f64,
+    })
+}
+
+/// Xtra attributes are a size, name length, name and value count followed by values made of a
+/// size, type and little-endian data
+fn rating_from_xtra(xtra: &[u8]) -> Option<i32> {
+    let mut rest = xtra;
+    while rest.len() >= 8 {
+        let size = u32::from_be_bytes(rest[..4].try_into().ok()?) as usize;
+        let attribute = rest.get(..size).filter(|_| size >= 8)?;
+        let name_len = u32::from_be_bytes(attribute[4..8].try_into().ok()?) as usize;
+        if attribute.get(8..8 + name_len)? == XTRA_RATING_NAME {
+            let value = attribute.get(12 + name_len..)?;
+            let value_size = u32::from_be_bytes(value.get(..4)?.try_into().ok()?) as usize;
+            let value_type = u16::from_be_bytes(value.get(4..6)?.try_into().ok()?);
+            if value_type != XTRA_TYPE_INT64 {
+                return None;
+            }
+            let percent = u64::from_le_bytes(value.get(6..value_size)?.try_into().ok()?);
+            return Some(stars_from_percent(percent.min(100) as i32));
+        }
+        rest = &rest[size..];
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn mp4_box(box_type: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut data = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(box_type);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    fn read_rating(moov_payload: &[u8]) -> Option<i32> {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&mp4_box(b"ftyp", b"qt  \0\0\0\0")).unwrap();
+        file.write_all(&mp4_box(b"mdat", &[0; 64])).unwrap();
+        file.write_all(&mp4_box(b"moov", moov_payload)).unwrap();
+        read_rating_quicktime(file.path()).unwrap()
+    }
+
+    #[test]
+    fn reads_user_rating_key() {
+        let mut key = ((USER_RATING_KEY.len() + 8) as u32).to_be_bytes().to_vec();
+        key.extend_from_slice(b"mdta");
+        key.extend_from_slice(USER_RATING_KEY);
+        let mut keys = vec![0, 0, 0, 0, 0, 0, 0, 1];
+        keys.extend_from_slice(&key);
+
+        let mut data = DATA_TYPE_FLOAT32.to_be_bytes().to_vec();
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&4.0f32.to_be_bytes());
+        let item = mp4_box(&1u32.to_be_bytes(), &mp4_box(b"data", &data));
+
+        let mut meta = mp4_box(b"hdlr", &[0; 24]);
+        meta.extend(mp4_box(b"keys", &keys));
+        meta.extend(mp4_box(b"ilst", &item));
+
+        assert_eq!(read_rating(&mp4_box(b"meta", &meta)), Some(4));
+    }
+
+    #[test]
+    fn reads_xtra_shared_user_rating() {
+        let mut value = 14u32.to_be_bytes().to_vec();
+        value.extend_from_slice(&XTRA_TYPE_INT64.to_be_bytes());
+        value.extend_from_slice(&75u64.to_le_bytes());
+        let mut attribute = ((12 + XTRA_RATING_NAME.len() + value.len()) as u32)
+            .to_be_bytes()
+            .to_vec();
+        attribute.extend_from_slice(&(XTRA_RATING_NAME.len() as u32).to_be_bytes());
+        attribute.extend_from_slice(XTRA_RATING_NAME);
+        attribute.extend_from_slice(&1u32.to_be_bytes());
+        attribute.extend_from_slice(&value);
+
+        let udta = mp4_box(b"Xtra", &attribute);
+        assert_eq!(read_rating(&mp4_box(b"udta", &udta)), Some(4));
+    }
+
+    #[test]
+    fn empty_moov_has_no_rating() {
+        assert_eq!(read_rating(&[]), None);
+    }
+}
@@ -0,0 +1,213 @@
+use crate::{get_modified_date, FileMeta};
+use anyhow::{anyhow, Result};
+use chrono::format::{Item, StrftimeItems};
+use std::path::Path;
+
+const DEFAULT_DATE_FORMAT: &str = "%Y%m%d_%H%M%S";
+
+#[derive(Clone, PartialEq, Debug)]
+enum TemplatePart {
+    Literal(String),
+    /// Capture date with a strftime format, falls back to the modification date
+    Date(String),
+    Model,
+    Rating,
+    Label,
+    /// Position of the file in the run, zero-padded to the width
+    Seq(usize),
+    /// Stem of the original file name
+    Orig,
+}
+
+/// File name template such as `{date:%Y%m%d}_{model}_{rating}stars_{orig}`, `{{` and `}}`
+/// are literal braces. The extension of the original file is kept
+#[derive(Clone, PartialEq, Debug)]
+pub struct NameTemplate {
+    parts: Vec<TemplatePart>,
+}
+
+impl NameTemplate {
+    pub fn parse(template: &str) -> Result<NameTemplate, String> {
+        let mut parts: Vec<TemplatePart> = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut field = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => field.push(c),
+                            None => return Err(format!("Unclosed field {{{field}")),
+                        }
+                    }
+                    if !literal.is_empty() {
+                        parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(parse_field(&field)?);
+                }
+                '}' => return Err("Unmatched } in template, use }} for a literal brace".into()),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Literal(literal));
+        }
+        if parts.is_empty() {
+            return Err("Template is empty".into());
+        }
+        Ok(NameTemplate { parts })
+    }
+
+    /// New file stem of the file, `seq` is the position of the file in the run
+    pub fn render(&self, path: &Path, meta: &FileMeta, seq: usize) -> Result<String> {
+        let mut name = String::new();
+        for part in &self.parts {
+            match part {
+                TemplatePart::Literal(literal) => name.push_str(literal),
+                TemplatePart::Date(format) => {
+                    let date = match meta.capture_date {
+                        Some(date) => date,
+                        None => get_modified_date(path)?,
+                    };
+                    name.push_str(&date.format(format).to_string());
+                }
+                TemplatePart::Model => {
+                    name.push_str(meta.camera.as_deref().unwrap_or("unknown"));
+                }
+                TemplatePart::Rating => name.push_str(&meta.rating.unwrap_or(0).to_string()),
+                TemplatePart::Label => {
+                    name.push_str(meta.label.as_deref().unwrap_or("unlabeled"));
+                }
+                TemplatePart::Seq(width) => name.push_str(&format!("{seq:0width$}")),
+                TemplatePart::Orig => {
+                    let stem = path
+                        .file_stem()
+                        .ok_or_else(|| anyhow!("{:?} has no file name", path))?;
+                    name.push_str(&stem.to_string_lossy());
+                }
+            }
+        }
+        let name = sanitize_file_name(&name);
+        if name.is_empty() || name == "." || name == ".." {
+            anyhow::bail!("Template rendered an invalid file name {name:?} for {path:?}");
+        }
+        Ok(name)
+    }
+}
+
+fn parse_field(field: &str) -> Result<TemplatePart, String> {
+    let (name, argument) = match field.split_once(':') {
+        Some((name, argument)) => (name, Some(argument)),
+        None => (field, None),
+    };
+    let part = match (name, argument) {
+        ("date", format) => {
+            let format = format.unwrap_or(DEFAULT_DATE_FORMAT);
+            if StrftimeItems::new(format).any(|item| item == Item::Error) {
+                return Err(format!("Invalid date format {format:?}"));
+            }
+            TemplatePart::Date(format.to_string())
+        }
+        ("seq", width) => {
+            let width = match width {
+                Some(width) => width
+                    .parse()
+                    .map_err(|_| format!("Invalid sequence width {width:?}"))?,
+                None => 1,
+            };
+            TemplatePart::Seq(width)
+        }
+        ("model", None) => TemplatePart::Model,
+        ("rating", None) => TemplatePart::Rating,
+        ("label", None) => TemplatePart::Label,
+        ("orig", None) => TemplatePart::Orig,
+        ("model" | "rating" | "label" | "orig", Some(_)) => {
+            return Err(format!("Field {name:?} takes no argument"))
+        }
+        _ => {
+            return Err(format!(
+                "Unknown field {{{field}}}, expected one of date, model, rating, label, seq, orig"
+            ))
+        }
+    };
+    Ok(part)
+}
+
+/// Replaces path separators and characters most filesystems reject
+fn sanitize_file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn meta() -> FileMeta {
+        FileMeta {
+            rating: Some(4),
+            label: None,
+            capture_date: NaiveDate::from_ymd_opt(2024, 7, 3)
+                .unwrap()
+                .and_hms_opt(14, 5, 9),
+            camera: Some("Canon EOS R5".to_string()),
+            ..FileMeta::default()
+        }
+    }
+
+    fn render(template: &str, seq: usize) -> String {
+        NameTemplate::parse(template)
+            .unwrap()
+            .render(Path::new("trip/IMG_0001.jpg"), &meta(), seq)
+            .unwrap()
+    }
+
+    #[test]
+    fn renders_fields() {
+        assert_eq!(
+            render("{date:%Y%m%d}_{model}_{rating}stars_{orig}", 1),
+            "20240703_Canon EOS R5_4stars_IMG_0001"
+        );
+        assert_eq!(render("{date}", 1), "20240703_140509");
+        assert_eq!(render("{label}-{seq:3}", 12), "unlabeled-012");
+        assert_eq!(render("{{{seq}}}", 7), "{7}");
+    }
+
+    #[test]
+    fn replaces_path_separators() {
+        assert_eq!(render("{date:%Y/%m/%d}", 1), "2024_07_03");
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        for template in [
+            "",
+            "{camera}",
+            "{date:%Q}",
+            "{seq:x}",
+            "{orig",
+            "a}b",
+            "{rating:2}",
+        ] {
+            assert!(NameTemplate::parse(template).is_err(), "{template}");
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use crate::{FileMeta, MissingRating};
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Operator {
+    MoreEqual,
+    LessEqual,
+    More,
+    Less,
+    Equal,
+    NotEqual,
+}
+
+impl Operator {
+    /// Longer operators first so `>=` isn't read as `>`
+    const SYMBOLS: [(&'static str, Operator); 7] = [
+        (">=", Operator::MoreEqual),
+        ("<=", Operator::LessEqual),
+        ("!=", Operator::NotEqual),
+        ("==", Operator::Equal),
+        (">", Operator::More),
+        ("<", Operator::Less),
+        ("=", Operator::Equal),
+    ];
+
+    fn compare<T: PartialOrd>(self, value: T, expected: T) -> bool {
+        match self {
+            Operator::MoreEqual => value >= expected,
+            Operator::LessEqual => value <= expected,
+            Operator::More => value > expected,
+            Operator::Less => value < expected,
+            Operator::Equal => value == expected,
+            Operator::NotEqual => value != expected,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Condition {
+    /// Unrated files are compared as 0 unless [`MissingRating::Skip`] is given
+    Rating(Operator, i32),
+    /// Case-insensitive, unlabeled files only match `!=`
+    Label(Operator, String),
+    Any,
+}
+
+impl Condition {
+    fn parse(condition: &str) -> Result<Condition, String> {
+        if condition == "*" {
+            return Ok(Condition::Any);
+        }
+        let invalid = || {
+            format!(
+                "Invalid condition {condition:?}, expected e.g. rating>=4, label=Red or * \
+                 for every file"
+            )
+        };
+        let (field, operator, value) = Operator::SYMBOLS
+            .iter()
+            .find_map(|(symbol, operator)| {
+                let (field, value) = condition.split_once(symbol)?;
+                Some((field.trim(), *operator, value.trim()))
+            })
+            .ok_or_else(invalid)?;
+        match field {
+            "rating" => {
+                let rating = value.parse().map_err(|_| invalid())?;
+                Ok(Condition::Rating(operator, rating))
+            }
+            "label" if matches!(operator, Operator::Equal | Operator::NotEqual) => {
+                if value.is_empty() {
+                    return Err(invalid());
+                }
+                Ok(Condition::Label(operator, value.to_lowercase()))
+            }
+            _ => Err(invalid()),
+        }
+    }
+
+    fn matches(&self, meta: &FileMeta, treat_missing_as: MissingRating) -> bool {
+        match self {
+            Condition::Rating(operator, rating) => match (meta.rating, treat_missing_as) {
+                (Some(file_rating), _) => operator.compare(file_rating, *rating),
+                (None, MissingRating::Zero) => operator.compare(0, *rating),
+                (None, MissingRating::Skip) => false,
+            },
+            Condition::Label(operator, label) => match &meta.label {
+                Some(file_label) => operator.compare(&file_label.to_lowercase(), label),
+                None => *operator == Operator::NotEqual,
+            },
+            Condition::Any => true,
+        }
+    }
+}
+
+/// Destination subdirectories picked by the first matching condition, parsed from a list
+/// like `rating>=5:Portfolio,rating>=3:Review,*:Other`
+#[derive(Clone, PartialEq, Debug)]
+pub struct Routes(Vec<(Condition, PathBuf)>);
+
+impl Routes {
+    pub fn parse(value: &str) -> Result<Routes, String> {
+        let mut routes: Vec<(Condition, PathBuf)> = Vec::new();
+        for rule in value.split(',') {
+            let (condition, dir) = rule.split_once(':').ok_or_else(|| {
+                format!("{rule:?} is not a condition and directory like rating>=5:Portfolio")
+            })?;
+            let dir = PathBuf::from(dir.trim());
+            // Routed files must stay inside the destination
+            let is_nested = dir
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)));
+            if dir.as_os_str().is_empty() || !is_nested {
+                return Err(format!(
+                    "Route directory {dir:?} must be a relative path inside the destination"
+                ));
+            }
+            routes.push((Condition::parse(condition.trim())?, dir));
+        }
+        Ok(Routes(routes))
+    }
+
+    /// Subdirectory of the first rule the file matches, `None` when no rule matches
+    pub fn destination(&self, meta: &FileMeta, treat_missing_as: MissingRating) -> Option<&Path> {
+        self.0
+            .iter()
+            .find(|(condition, _)| condition.matches(meta, treat_missing_as))
+            .map(|(_, dir)| dir.as_path())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(rating: Option<i32>, label: Option<&str>) -> FileMeta {
+        FileMeta {
+            rating,
+            label: label.map(str::to_string),
+            ..FileMeta::default()
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let routes =
+            Routes::parse("rating>=5:Portfolio, rating>=3:Review,label=red:Picks/red").unwrap();
+        let destination = |meta: FileMeta| {
+            routes
+                .destination(&meta, MissingRating::Zero)
+                .map(Path::to_path_buf)
+        };
+
+        assert_eq!(
+            destination(meta(Some(5), Some("Red"))),
+            Some(PathBuf::from("Portfolio"))
+        );
+        assert_eq!(
+            destination(meta(Some(3), None)),
+            Some(PathBuf::from("Review"))
+        );
+        assert_eq!(
+            destination(meta(Some(1), Some("Red"))),
+            Some(PathBuf::from("Picks/red"))
+        );
+        assert_eq!(destination(meta(None, None)), None);
+
+        let routes = Routes::parse("rating<1:Unrated,*:Rest").unwrap();
+        assert_eq!(
+            routes.destination(&meta(None, None), MissingRating::Zero),
+            Some(Path::new("Unrated"))
+        );
+        assert_eq!(
+            routes.destination(&meta(Some(2), None), MissingRating::Zero),
+            Some(Path::new("Rest"))
+        );
+    }
+
+    #[test]
+    fn unrated_files_follow_treat_missing_as() {
+        let routes = Routes::parse("rating<1:Rejects,rating!=3:Other").unwrap();
+        assert_eq!(
+            routes.destination(&meta(None, None), MissingRating::Zero),
+            Some(Path::new("Rejects"))
+        );
+        assert_eq!(
+            routes.destination(&meta(None, None), MissingRating::Skip),
+            None
+        );
+        assert_eq!(
+            routes.destination(&meta(Some(0), None), MissingRating::Skip),
+            Some(Path::new("Rejects"))
+        );
+
+        let routes = Routes::parse("rating>=4:Best,*:Rest").unwrap();
+        assert_eq!(
+            routes.destination(&meta(None, None), MissingRating::Skip),
+            Some(Path::new("Rest"))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_routes() {
+        for routes in [
+            "",
+            "rating>=5",
+            "rating>=x:Portfolio",
+            "stars>=5:Portfolio",
+            "label>Red:Red",
+            "rating>=5:",
+            "rating>=5:../Portfolio",
+            "rating>=5:/Portfolio",
+        ] {
+            assert!(Routes::parse(routes).is_err(), "{routes}");
+        }
+    }
+}
@@ -1,145 +1,254 @@
-use anyhow::{Error, Result};
+use crate::{MetadataError, PickFlag, XmpSearch};
+use anyhow::{anyhow, Error, Result};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime};
+use memchr::memmem;
+use std::fs;
 use std::fs::File;
-use std::io::{BufReader, Read, Seek, SeekFrom};
-use std::path::PathBuf;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use xmp_toolkit::{xmp_ns, XmpMeta};
+use xmp_toolkit::{xmp_ns, OpenFileOptions, XmpFile, XmpMeta, XmpValue};
 
-struct CircularBuffer<T> {
-    buffer: Vec<T>,
-    index: usize,
-}
+const XMP_START: &[u8] = b"<x:xmpmeta";
+const XMP_END: &[u8] = b"</x:xmpmeta>";
+const XMP_SEARCH_BUFFER_SIZE: usize = 4096 * 32;
+const MICROSOFT_PHOTO_NS: &str = "http://ns.microsoft.com/photo/1.0/";
+const DYNAMIC_MEDIA_NS: &str = "http://ns.adobe.com/xmp/1.0/DynamicMedia/";
 
-impl<T: Default + Clone + Copy> CircularBuffer<T> {
-    fn new(size: usize) -> CircularBuffer<T> {
-        CircularBuffer {
-            buffer: vec![Default::default(); size],
-            index: 0,
-        }
-    }
+pub fn read_xmp(filename: PathBuf, xmp_search: XmpSearch) -> Result<XmpMeta> {
+    let file = File::open(&filename)?;
 
-    fn push(&mut self, value: T) {
-        self.buffer[self.index] = value;
-        self.index = (self.index + 1) % self.buffer.len();
+    // The packet is usually near the end of videos, search the tail first
+    let mut xmp_data = extract_xmp_data(&file, true, xmp_search)?;
+    if xmp_data.is_none() {
+        xmp_data = extract_xmp_data(&file, false, xmp_search)?;
     }
-
-    fn get(&self, ith: usize) -> T {
-        if ith >= self.buffer.len() {
-            panic!("Index out of bounds");
-        }
-        self.buffer[(self.index + ith) % self.buffer.len()]
+    if xmp_data.is_none() {
+        xmp_data = extract_xmp_data_whole_file(&file, xmp_search)?;
     }
+    let Some(xmp_data) = xmp_data else {
+        return Err(MetadataError::XmpNotFound(filename).into());
+    };
 
-    fn iter(&self) -> CircularBufferIterator<'_, T> {
-        CircularBufferIterator::<T> {
-            buffer: self,
-            current_index: 0,
-        }
-    }
+    Ok(XmpMeta::from_str(decode_xmp_data(&xmp_data)?)?)
 }
 
-struct CircularBufferIterator<'a, T> {
-    buffer: &'a CircularBuffer<T>,
-    current_index: usize,
+/// Reads the rating from the embedded XMP packet, `None` when the packet has no rating
+pub fn read_rating_xmp(
+    filename: PathBuf,
+    rating_tag: &str,
+    xmp_search: XmpSearch,
+) -> Result<Option<i32>> {
+    rating_from_xmp(&read_xmp(filename, xmp_search)?, rating_tag)
 }
 
-impl<T: Copy + Default> Iterator for CircularBufferIterator<'_, T> {
-    type Item = T;
+pub fn rating_from_xmp(xmp_meta: &XmpMeta, rating_tag: &str) -> Result<Option<i32>> {
+    let Some((namespace, name)) = xmp_property(rating_tag) else {
+        anyhow::bail!("{rating_tag} is not an XMP tag");
+    };
+    Ok(xmp_meta
+        .property(&namespace, name)
+        .map(|prop| prop.value.parse::<i32>())
+        .transpose()?)
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.current_index < self.buffer.buffer.len() {
-            let value = self.buffer.get(self.current_index);
-            self.current_index += 1;
-            Some(value)
-        } else {
-            None
-        }
-    }
+/// Maps an exiv2 style `Xmp.<prefix>.<name>` key to its XMP namespace and property name
+fn xmp_property(tag: &str) -> Option<(String, &str)> {
+    let (prefix, name) = tag.strip_prefix("Xmp.")?.split_once('.')?;
+    let namespace = match prefix {
+        "xmp" => xmp_ns::XMP.to_string(),
+        // Not registered by the XMP toolkit by default
+        "MicrosoftPhoto" => MICROSOFT_PHOTO_NS.to_string(),
+        prefix => XmpMeta::namespace_uri(prefix)?,
+    };
+    Some((namespace, name))
 }
 
-impl<T: Default + Clone + Copy + PartialEq> CircularBuffer<T> {
-    fn contains(&self, subarray: &[T]) -> bool {
-        if subarray.len() > self.buffer.len() {
-            return false;
-        }
+pub fn create_date_from_xmp(xmp_meta: &XmpMeta) -> Option<NaiveDateTime> {
+    parse_xmp_date(&xmp_meta.property(xmp_ns::XMP, "CreateDate")?.value)
+}
 
-        for (i, item) in self.iter().enumerate() {
-            if item != subarray[i] {
-                return false;
-            }
-        }
+pub fn label_from_xmp(xmp_meta: &XmpMeta) -> Option<String> {
+    Some(xmp_meta.property(xmp_ns::XMP, "Label")?.value).filter(|label| !label.is_empty())
+}
+
+/// Reads `xmpDM:pick`, `None` when the packet has no pick flag
+pub fn flag_from_xmp(xmp_meta: &XmpMeta) -> Option<PickFlag> {
+    xmp_meta
+        .property(DYNAMIC_MEDIA_NS, "pick")
+        .map(|pick| PickFlag::from_pick(&pick.value))
+}
 
-        true
+fn parse_xmp_date(date: &str) -> Option<NaiveDateTime> {
+    // XMP dates are ISO 8601 with optional time, fractional seconds and timezone
+    if let Ok(date) = DateTime::parse_from_rfc3339(date) {
+        return Some(date.naive_local());
     }
+    ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%dT%H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(date, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .ok()
+                .map(|date| date.and_time(NaiveTime::MIN))
+        })
 }
 
-const XMP_START: &[u8] = b"<x:xmpmeta";
-const XMP_END: &[u8] = b"</x:xmpmeta>";
-const XMP_SEARCH_BUFFER_SIZE: usize = 4096 * 32;
-const XMP_END_SEARCH_SPACE_SIZE: usize = 4096 * 256;
-const XMP_MAX_SEARCH_SPACE_SIZE: usize = 4096 * 256;
+fn decode_xmp_data(xmp_data: &[u8]) -> Result<&str> {
+    std::str::from_utf8(xmp_data).map_err(|e| anyhow!("XMP data is not valid UTF-8: {e}"))
+}
 
-pub fn read_rating_xmp(filename: PathBuf) -> Result<i32> {
-    let xmp_data = extract_xmp_data(filename.clone(), true)?
-        .or_else(|| extract_xmp_data(filename, false).unwrap());
+pub fn find_sidecar(filename: &Path) -> Option<PathBuf> {
+    // Both `<name>.<ext>.xmp` and `<name>.xmp` naming conventions are in use
+    let mut full_name_sidecar = filename.as_os_str().to_owned();
+    full_name_sidecar.push(".xmp");
 
-    if xmp_data.is_none() {
-        anyhow::bail!("XMP data not found in the file.");
+    [
+        PathBuf::from(full_name_sidecar),
+        filename.with_extension("xmp"),
+    ]
+    .into_iter()
+    .find(|sidecar| sidecar.is_file())
+}
+
+pub fn read_sidecar(filename: PathBuf) -> Result<XmpMeta> {
+    let xmp_data = fs::read_to_string(filename)?;
+    Ok(XmpMeta::from_str(&xmp_data)?)
+}
+
+/// Reads the rating from a sidecar, `None` when `rating_tag` isn't an XMP tag
+pub fn sidecar_rating(xmp_meta: &XmpMeta, rating_tag: &str) -> Result<Option<i32>> {
+    match xmp_property(rating_tag) {
+        Some(_) => rating_from_xmp(xmp_meta, rating_tag),
+        None => Ok(None),
     }
+}
 
-    let xmp_meta = XmpMeta::from_str(std::str::from_utf8(&xmp_data.as_ref().unwrap()).unwrap());
+pub fn write_rating_xmp(filename: PathBuf, rating_tag: &str, rating: i32) -> Result<()> {
+    let Some((namespace, name)) = xmp_property(rating_tag) else {
+        anyhow::bail!("{rating_tag} is not an XMP tag");
+    };
+    if namespace == MICROSOFT_PHOTO_NS {
+        XmpMeta::register_namespace(MICROSOFT_PHOTO_NS, "MicrosoftPhoto")?;
+    }
+    update_xmp(filename, |xmp_meta| {
+        Ok(xmp_meta.set_property(&namespace, name, &XmpValue::new(rating.to_string()))?)
+    })
+}
 
-    Ok(xmp_meta
-        .unwrap()
-        .property(xmp_ns::XMP, "Rating")
-        .map_or(0, |prop| prop.value.parse::<i32>().unwrap()))
+/// Writes the label, an empty label removes the property
+pub fn write_label_xmp(filename: PathBuf, label: &str) -> Result<()> {
+    update_xmp(filename, |xmp_meta| match label {
+        "" => Ok(xmp_meta.delete_property(xmp_ns::XMP, "Label")?),
+        label => {
+            Ok(xmp_meta.set_property(xmp_ns::XMP, "Label", &XmpValue::new(label.to_string()))?)
+        }
+    })
+}
+
+fn update_xmp(filename: PathBuf, update: impl FnOnce(&mut XmpMeta) -> Result<()>) -> Result<()> {
+    let mut xmp_file = XmpFile::new()?;
+    xmp_file.open_file(&filename, OpenFileOptions::default().for_update())?;
+
+    let mut xmp_meta = match xmp_file.xmp() {
+        Some(xmp_meta) => xmp_meta,
+        None => XmpMeta::new()?,
+    };
+    update(&mut xmp_meta)?;
+
+    if !xmp_file.can_put_xmp(&xmp_meta) {
+        anyhow::bail!("Unable to write XMP data to {:?}", filename);
+    }
+    xmp_file.put_xmp(&xmp_meta)?;
+    xmp_file.close();
+    Ok(())
 }
 
 fn extract_xmp_data(
-    filename: PathBuf,
+    file: &File,
     read_from_end_of_file: bool,
+    xmp_search: XmpSearch,
 ) -> Result<Option<Vec<u8>>, Error> {
-    let file = File::open(filename).unwrap();
+    let file_size = file.metadata()?.len();
     let mut reader = BufReader::new(file);
-    let mut buffer = vec![0; XMP_SEARCH_BUFFER_SIZE];
-    let mut total_bytes_read = 0;
-    let mut start_buffer = CircularBuffer::new(XMP_START.len());
-    let mut end_buffer = CircularBuffer::new(XMP_END.len());
-    let mut start_found = false;
-    let mut xmp_data = XMP_START.to_vec();
 
-    if read_from_end_of_file {
-        reader.seek(SeekFrom::End(-(XMP_END_SEARCH_SPACE_SIZE as i64)))?;
+    let search_space_size = if read_from_end_of_file {
+        // Files smaller than the search space are searched from the start
+        let search_space_size = file_size.min(xmp_search.tail_bytes as u64);
+        reader.seek(SeekFrom::End(-(search_space_size as i64)))?;
+        xmp_search.tail_bytes
+    } else {
+        reader.seek(SeekFrom::Start(0))?;
+        xmp_search.max_bytes
+    };
+
+    scan_xmp_data(reader, search_space_size)
+}
+
+/// Scans files up to the whole file cap from start to end, catching packets in the middle
+/// of files the tail and head searches miss
+fn extract_xmp_data_whole_file(
+    file: &File,
+    xmp_search: XmpSearch,
+) -> Result<Option<Vec<u8>>, Error> {
+    let file_size = file.metadata()?.len();
+    // Files within the head search space were already searched whole
+    if file_size <= xmp_search.max_bytes as u64 || file_size > xmp_search.whole_file_bytes as u64 {
+        return Ok(None);
     }
+    let mut reader = BufReader::new(file);
+    reader.seek(SeekFrom::Start(0))?;
+
+    scan_xmp_data(reader, file_size as usize)
+}
 
-    while let Ok(n) = reader.read(&mut buffer) {
+/// Searches the first `search_space_size` bytes of the reader for the XMP packet
+fn scan_xmp_data<R: Read>(
+    mut reader: R,
+    search_space_size: usize,
+) -> Result<Option<Vec<u8>>, Error> {
+    let start_finder = memmem::Finder::new(XMP_START);
+    let end_finder = memmem::Finder::new(XMP_END);
+    let mut buffer = vec![0; XMP_SEARCH_BUFFER_SIZE];
+    let mut total_bytes_read = 0;
+    // Bytes which may still be part of the packet, including a possible partial marker
+    let mut data: Vec<u8> = Vec::new();
+    let mut start: Option<usize> = None;
+    let mut end_search_offset = 0;
+
+    loop {
+        let n = match reader.read(&mut buffer) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
         if n == 0 {
             break;
         }
+        let n = n.min(search_space_size - total_bytes_read);
+        total_bytes_read += n;
+        data.extend_from_slice(&buffer[..n]);
 
-        for &byte in buffer.iter().take(n) {
-            if !start_found {
-                start_buffer.push(byte);
-            } else {
-                end_buffer.push(byte);
-            }
-            total_bytes_read += 1;
-
-            if total_bytes_read > XMP_MAX_SEARCH_SPACE_SIZE {
-                return Ok(None);
-            }
-
-            if !start_found && start_buffer.contains(XMP_START) {
-                start_found = true;
+        if start.is_none() {
+            start = start_finder.find(&data);
+            if start.is_none() {
+                // Keep just enough of the tail to catch a start marker split between reads
+                data.drain(..data.len().saturating_sub(XMP_START.len() - 1));
             }
+        }
 
-            if start_found {
-                xmp_data.push(byte);
+        if let Some(start) = start {
+            let search_offset = end_search_offset.max(start + XMP_START.len());
+            // Only bytes up to and including the end marker are captured
+            if let Some(end) = end_finder.find(&data[search_offset..]) {
+                let end = search_offset + end + XMP_END.len();
+                return Ok(Some(data[start..end].to_vec()));
             }
+            end_search_offset = data.len().saturating_sub(XMP_END.len() - 1);
+        }
 
-            if start_found && end_buffer.contains(XMP_END) {
-                return Ok(Some(xmp_data));
-            }
+        if total_bytes_read >= search_space_size {
+            break;
         }
     }
     Ok(None)
@@ -174,3 +283,269 @@ fn extract_xmp_data(
 //     }
 //     return Ok(None);
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DEFAULT_XMP_MAX_BYTES;
+    use std::io::Write;
+
+    const UTF8_XMP_PACKET: &str = "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\
+        <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\
+        <rdf:Description xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\" \
+        xmlns:dc=\"http://purl.org/dc/elements/1.1/\" xmp:Rating=\"4\">\
+        <dc:rights>© Zoë Ł. Müller, 東京</dc:rights>\
+        </rdf:Description></rdf:RDF></x:xmpmeta>";
+
+    #[test]
+    fn flag_from_xmp_reads_xmp_dm_pick() {
+        let packet = |pick: &str| {
+            let packet = format!(
+                "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\
+                <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\
+                <rdf:Description xmlns:xmpDM=\"{DYNAMIC_MEDIA_NS}\" {pick}/>\
+                </rdf:RDF></x:xmpmeta>"
+            );
+            XmpMeta::from_str(&packet).unwrap()
+        };
+        assert_eq!(
+            flag_from_xmp(&packet("xmpDM:pick=\"1\"")),
+            Some(PickFlag::Pick)
+        );
+        assert_eq!(
+            flag_from_xmp(&packet("xmpDM:pick=\"-1\"")),
+            Some(PickFlag::Reject)
+        );
+        assert_eq!(
+            flag_from_xmp(&packet("xmpDM:pick=\"0\"")),
+            Some(PickFlag::Unflagged)
+        );
+        assert_eq!(flag_from_xmp(&packet("")), None);
+    }
+
+    #[test]
+    fn scan_xmp_data_with_utf8_characters() {
+        let mut file_data = vec![0xff, 0xd8, 0x00, 0xc3];
+        file_data.extend_from_slice(UTF8_XMP_PACKET.as_bytes());
+        // Trailing bytes which are not valid UTF-8
+        file_data.extend_from_slice(&[0xe6, 0x9d, 0xff, 0x00]);
+
+        let xmp_data = scan_xmp_data(file_data.as_slice(), DEFAULT_XMP_MAX_BYTES)
+            .unwrap()
+            .unwrap();
+        assert_eq!(decode_xmp_data(&xmp_data).unwrap(), UTF8_XMP_PACKET);
+    }
+
+    /// Hands out a few bytes per read so markers end up split between reads
+    struct ChunkedReader<'a> {
+        data: &'a [u8],
+    }
+
+    impl Read for ChunkedReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.data.len().min(buf.len()).min(5);
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    /// Fails every read
+    struct FailingReader;
+
+    impl Read for FailingReader {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("disk error"))
+        }
+    }
+
+    #[test]
+    fn scan_xmp_data_reports_read_errors() {
+        assert!(scan_xmp_data(FailingReader, DEFAULT_XMP_MAX_BYTES).is_err());
+    }
+
+    #[test]
+    fn scan_xmp_data_with_markers_split_between_reads() {
+        let mut file_data = b"\x00<x:xmp".to_vec();
+        file_data.extend_from_slice(UTF8_XMP_PACKET.as_bytes());
+        file_data.extend_from_slice(b"</x:xmp");
+
+        let xmp_data = scan_xmp_data(ChunkedReader { data: &file_data }, DEFAULT_XMP_MAX_BYTES)
+            .unwrap()
+            .unwrap();
+        assert_eq!(decode_xmp_data(&xmp_data).unwrap(), UTF8_XMP_PACKET);
+    }
+
+    #[test]
+    fn scan_xmp_data_without_end_marker() {
+        let file_data = &UTF8_XMP_PACKET.as_bytes()[..UTF8_XMP_PACKET.len() - XMP_END.len()];
+        assert!(scan_xmp_data(file_data, DEFAULT_XMP_MAX_BYTES)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn scan_xmp_data_of_exact_packet() {
+        let xmp_data = scan_xmp_data(UTF8_XMP_PACKET.as_bytes(), DEFAULT_XMP_MAX_BYTES)
+            .unwrap()
+            .unwrap();
+        assert_eq!(decode_xmp_data(&xmp_data).unwrap(), UTF8_XMP_PACKET);
+
+        let misspelled = UTF8_XMP_PACKET.replacen("<x:xmpmeta", "<x:xmpmetb", 1);
+        assert!(scan_xmp_data(misspelled.as_bytes(), DEFAULT_XMP_MAX_BYTES)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn scan_xmp_data_after_partial_start_markers() {
+        let mut file_data = b"<x:xmp<x:<x:xmpme".to_vec();
+        file_data.extend_from_slice(UTF8_XMP_PACKET.as_bytes());
+
+        let xmp_data = scan_xmp_data(ChunkedReader { data: &file_data }, DEFAULT_XMP_MAX_BYTES)
+            .unwrap()
+            .unwrap();
+        assert_eq!(decode_xmp_data(&xmp_data).unwrap(), UTF8_XMP_PACKET);
+    }
+
+    #[test]
+    fn scan_xmp_data_returns_first_packet() {
+        let mut file_data = b"0123456789".to_vec();
+        file_data.extend_from_slice(UTF8_XMP_PACKET.as_bytes());
+        file_data.extend_from_slice(b"0123456789");
+        file_data.extend_from_slice(
+            UTF8_XMP_PACKET
+                .replace("Rating=\"4\"", "Rating=\"5\"")
+                .as_bytes(),
+        );
+
+        let xmp_data = scan_xmp_data(ChunkedReader { data: &file_data }, DEFAULT_XMP_MAX_BYTES)
+            .unwrap()
+            .unwrap();
+        assert_eq!(decode_xmp_data(&xmp_data).unwrap(), UTF8_XMP_PACKET);
+    }
+
+    #[test]
+    fn scan_xmp_data_shorter_than_markers() {
+        for file_data in [&b""[..], b"<x:xmp", b"<x:xmpmeta</x:xmp"] {
+            assert!(scan_xmp_data(file_data, DEFAULT_XMP_MAX_BYTES)
+                .unwrap()
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn scan_xmp_data_stops_at_search_space_size() {
+        let mut file_data = vec![0; 100];
+        file_data.extend_from_slice(UTF8_XMP_PACKET.as_bytes());
+
+        assert!(scan_xmp_data(file_data.as_slice(), file_data.len() - 1)
+            .unwrap()
+            .is_none());
+        assert!(scan_xmp_data(file_data.as_slice(), file_data.len())
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn extract_xmp_data_within_search_space() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(UTF8_XMP_PACKET.as_bytes()).unwrap();
+        file.write_all(&[0; 4096]).unwrap();
+        let file = file.reopen().unwrap();
+        let xmp_search = XmpSearch {
+            tail_bytes: 4096,
+            max_bytes: 4096,
+            whole_file_bytes: 0,
+        };
+
+        // The packet isn't within the last 4096 bytes, only the start of the file has it
+        assert!(extract_xmp_data(&file, true, xmp_search).unwrap().is_none());
+        assert!(extract_xmp_data(&file, false, xmp_search)
+            .unwrap()
+            .is_some());
+        let xmp_search = XmpSearch {
+            tail_bytes: 4096 + UTF8_XMP_PACKET.len(),
+            ..xmp_search
+        };
+        assert!(extract_xmp_data(&file, true, xmp_search).unwrap().is_some());
+    }
+
+    #[test]
+    fn extract_xmp_data_from_the_middle_of_small_files() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&[0; 4096]).unwrap();
+        file.write_all(UTF8_XMP_PACKET.as_bytes()).unwrap();
+        file.write_all(&[0; 4096]).unwrap();
+        let file = file.reopen().unwrap();
+        let mut xmp_search = XmpSearch {
+            tail_bytes: 4096,
+            max_bytes: 4096,
+            whole_file_bytes: 4096,
+        };
+
+        assert!(extract_xmp_data(&file, true, xmp_search).unwrap().is_none());
+        assert!(extract_xmp_data(&file, false, xmp_search)
+            .unwrap()
+            .is_none());
+        assert!(extract_xmp_data_whole_file(&file, xmp_search)
+            .unwrap()
+            .is_none());
+
+        xmp_search.whole_file_bytes = 4096 * 4;
+        assert!(extract_xmp_data_whole_file(&file, xmp_search)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn scan_xmp_data_in_avif_and_jxl_boxes() {
+        for file_data in [
+            include_bytes!("../tests/fixtures/sample.avif").as_slice(),
+            include_bytes!("../tests/fixtures/sample.jxl").as_slice(),
+        ] {
+            let xmp_data = scan_xmp_data(file_data, DEFAULT_XMP_MAX_BYTES)
+                .unwrap()
+                .unwrap();
+            assert!(decode_xmp_data(&xmp_data)
+                .unwrap()
+                .contains("xmp:Rating=\"4\""));
+        }
+    }
+
+    #[test]
+    fn read_xmp_without_xmp_data() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&[0xff, 0xd8, 0xff, 0xe0, 0x00, 0x10])
+            .unwrap();
+
+        let err = read_xmp(file.path().to_path_buf(), XmpSearch::default()).unwrap_err();
+        assert!(err.to_string().contains("XMP data not found"));
+    }
+
+    #[test]
+    fn parse_xmp_date_formats() {
+        let expected = NaiveDate::from_ymd_opt(2023, 6, 1)
+            .unwrap()
+            .and_hms_opt(12, 34, 56)
+            .unwrap();
+        assert_eq!(parse_xmp_date("2023-06-01T12:34:56"), Some(expected));
+        assert_eq!(
+            parse_xmp_date("2023-06-01T12:34:56.120"),
+            Some(expected + chrono::TimeDelta::milliseconds(120))
+        );
+        assert_eq!(parse_xmp_date("2023-06-01T12:34:56+02:00"), Some(expected));
+        assert_eq!(
+            parse_xmp_date("2023-06-01"),
+            NaiveDate::from_ymd_opt(2023, 6, 1)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+        );
+        assert_eq!(parse_xmp_date("June 1st"), None);
+    }
+
+    #[test]
+    fn decode_xmp_data_rejects_invalid_utf8() {
+        assert!(decode_xmp_data(&[b'<', 0xe6, 0x9d]).is_err());
+    }
+}
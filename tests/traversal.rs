@@ -0,0 +1,461 @@
+use rust_exif::{
+    collect_entries, evaluate, traverse, write_rating, ComparisonCommand, Config, Entry, Filter,
+    LabelMatch, MissingRating, RatingFrom, RatingSource, RawStem, XmpSearch, DEFAULT_RATING_TAG,
+};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+const SAMPLE_TIFF: &[u8] = include_bytes!("fixtures/sample.tif");
+
+/// Creates the files, given relative to the root, with the content of the sample TIFF
+fn source_tree(files: &[&str]) -> TempDir {
+    let src = tempfile::tempdir().unwrap();
+    for file in files {
+        let path = src.path().join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, SAMPLE_TIFF).unwrap();
+    }
+    src
+}
+
+fn config(src: &Path) -> Config {
+    Config {
+        src: src.to_path_buf(),
+        raw_src: None,
+        flat_raw_src: false,
+        exclude: Vec::new(),
+        flip_exclusion: false,
+        exclude_recursive: false,
+        include_videos: false,
+        include_raw_only: false,
+        orphan_raws: false,
+        match_raws: false,
+        raw_stem: RawStem::Full,
+        max_depth: None,
+        max_scan: None,
+        follow_symlinks: false,
+        include_glob: Vec::new(),
+        exclude_glob: Vec::new(),
+        no_ignore: false,
+        skip_dir: None,
+        group_bursts: false,
+        extensions: Vec::new(),
+        exclude_extensions: Vec::new(),
+    }
+}
+
+fn filter(threshold: i32, comparison_command: ComparisonCommand) -> Filter {
+    Filter {
+        threshold,
+        comparison_command,
+        min_rating: None,
+        max_rating: None,
+        unrated: false,
+        treat_missing_as: MissingRating::Zero,
+        label: Vec::new(),
+        label_match: LabelMatch::Any,
+        flag: None,
+        include_tag: None,
+        ignore_tag: None,
+        after: None,
+        before: None,
+        include_undated: false,
+        newer_than: None,
+        older_than: None,
+        camera: None,
+        lens: None,
+        fix_orientation: false,
+        has_gps: None,
+        min_iso: None,
+        max_iso: None,
+        min_fnumber: None,
+        max_fnumber: None,
+        min_exposure_time: None,
+        max_exposure_time: None,
+        include_missing_exposure: false,
+        inverse: false,
+        prefer_sidecar: false,
+        rating_source: RatingSource::Embedded,
+        rating_from: RatingFrom::Primary,
+        rating_tag: DEFAULT_RATING_TAG.to_string(),
+        xmp_search: XmpSearch::default(),
+    }
+}
+
+/// Paths of the collected entries relative to `src`
+fn relative_paths(src: &Path, entries: &[Entry]) -> Vec<PathBuf> {
+    entries
+        .iter()
+        .map(|entry| entry.path.strip_prefix(src).unwrap().to_path_buf())
+        .collect()
+}
+
+fn relative_paths_of(src: &Path, paths: &[PathBuf]) -> Vec<PathBuf> {
+    paths
+        .iter()
+        .map(|path| path.strip_prefix(src).unwrap().to_path_buf())
+        .collect()
+}
+
+fn collected(config: &Config) -> Vec<PathBuf> {
+    relative_paths(&config.src, &collect_entries(config).unwrap())
+}
+
+fn paths(paths: &[&str]) -> Vec<PathBuf> {
+    paths.iter().map(PathBuf::from).collect()
+}
+
+#[test]
+fn exclusion_skips_top_level_directories() {
+    let src = source_tree(&["a.jpg", "trip/b.jpg", "skip/c.jpg", "trip/skip/d.jpg"]);
+    let mut config = config(src.path());
+    config.exclude = vec!["skip".to_string()];
+
+    assert_eq!(
+        collected(&config),
+        paths(&["a.jpg", "trip/b.jpg", "trip/skip/d.jpg"])
+    );
+
+    config.exclude_recursive = true;
+    assert_eq!(collected(&config), paths(&["a.jpg", "trip/b.jpg"]));
+}
+
+#[test]
+fn flipped_exclusion_selects_top_level_directories() {
+    let src = source_tree(&["a.jpg", "trip/b.jpg", "trip/other/c.jpg", "other/d.jpg"]);
+    let mut config = config(src.path());
+    config.exclude = vec!["trip".to_string()];
+    config.flip_exclusion = true;
+
+    // Files next to the directories are kept, the selected directories are walked whole
+    assert_eq!(
+        collected(&config),
+        paths(&["a.jpg", "trip/b.jpg", "trip/other/c.jpg"])
+    );
+}
+
+#[test]
+fn videos_are_only_collected_when_included() {
+    let src = source_tree(&["a.jpg", "clip.mov", "clip.MP4", "notes.txt"]);
+    let mut config = config(src.path());
+
+    assert_eq!(collected(&config), paths(&["a.jpg"]));
+
+    config.include_videos = true;
+    assert_eq!(
+        collected(&config),
+        paths(&["a.jpg", "clip.MP4", "clip.mov"])
+    );
+}
+
+#[test]
+fn extensions_are_selected_and_excluded() {
+    let src = source_tree(&["a.jpg", "b.PNG", "c.heic", "clip.mov", "d.CR2"]);
+    let mut config = config(src.path());
+    config.extensions = vec!["jpg".to_string(), ".png".to_string()];
+    assert_eq!(collected(&config), paths(&["a.jpg", "b.PNG"]));
+
+    // Exclusions apply to videos and raws as well
+    config.extensions = Vec::new();
+    config.exclude_extensions = vec!["PNG".to_string(), "mov".to_string(), "cr2".to_string()];
+    config.include_videos = true;
+    config.include_raw_only = true;
+    assert_eq!(collected(&config), paths(&["a.jpg", "c.heic"]));
+
+    config.exclude_extensions = vec![" ".to_string()];
+    assert!(collect_entries(&config).is_err());
+}
+
+#[test]
+fn exifignore_files_apply_to_their_subtree() {
+    let src = source_tree(&[
+        "a.jpg",
+        "skip.jpg",
+        "other/skip.jpg",
+        "raw/x.jpg",
+        "trip/b.jpg",
+        "trip/keep.jpg",
+        "trip/skip.jpg",
+        "trip/day/c.jpg",
+        "trip/day/skip.jpg",
+        "zzz/skip.jpg",
+    ]);
+    fs::write(src.path().join(".exifignore"), "skip.jpg\nraw/\n").unwrap();
+    fs::write(
+        src.path().join("trip/.exifignore"),
+        "!skip.jpg\nday/c.jpg\n",
+    )
+    .unwrap();
+    let mut config = config(src.path());
+
+    // The nested file re-includes within trip only, zzz is walked after it was left
+    assert_eq!(
+        collected(&config),
+        paths(&[
+            "a.jpg",
+            "trip/b.jpg",
+            "trip/day/skip.jpg",
+            "trip/keep.jpg",
+            "trip/skip.jpg",
+        ])
+    );
+
+    config.no_ignore = true;
+    assert_eq!(collected(&config).len(), 10);
+}
+
+#[test]
+fn traversal_stops_over_max_scan() {
+    let src = source_tree(&["a.jpg", "trip/b.jpg", "trip/c.jpg"]);
+    let mut config = config(src.path());
+    config.max_scan = Some(3);
+    assert_eq!(collected(&config).len(), 3);
+
+    config.max_scan = Some(2);
+    let e = collect_entries(&config).unwrap_err();
+    assert!(e.to_string().contains("--max-scan"), "{e}");
+}
+
+#[cfg(unix)]
+#[test]
+fn unreadable_directories_are_skipped() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let src = source_tree(&["a.jpg", "locked/b.jpg", "open/c.jpg"]);
+    let locked = src.path().join("locked");
+    fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+    if fs::read_dir(&locked).is_ok() {
+        // Permissions don't apply to root
+        return;
+    }
+
+    let traversal = traverse(&config(src.path())).unwrap();
+    fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+    assert_eq!(
+        relative_paths(src.path(), &traversal.entries),
+        paths(&["a.jpg", "open/c.jpg"])
+    );
+    assert_eq!(traversal.skipped_dirs, 1);
+}
+
+#[test]
+fn raws_are_matched_next_to_images_and_in_raw_src() {
+    let src = source_tree(&["trip/a.jpg", "trip/a.CR2", "trip/b_c.jpg", "trip/c.jpg"]);
+    let raw_src = source_tree(&["trip/b.dng", "trip/a.nef"]);
+    let mut config = config(src.path());
+    config.match_raws = true;
+
+    // Raws are never entries of their own
+    let entries = collect_entries(&config).unwrap();
+    assert_eq!(
+        relative_paths(src.path(), &entries),
+        paths(&["trip/a.jpg", "trip/b_c.jpg", "trip/c.jpg"])
+    );
+    assert_eq!(entries[0].raw_paths, vec![src.path().join("trip/a.CR2")]);
+    assert!(entries[1].raw_paths.is_empty());
+
+    // A separate raw tree mirrors the source, `_c` marks edited copies of a raw
+    config.raw_src = Some(raw_src.path().to_path_buf());
+    let entries = collect_entries(&config).unwrap();
+    assert_eq!(
+        entries[0].raw_paths,
+        vec![raw_src.path().join("trip/a.nef")]
+    );
+    assert_eq!(
+        entries[1].raw_paths,
+        vec![raw_src.path().join("trip/b.dng")]
+    );
+    assert!(entries[2].raw_paths.is_empty());
+
+    let flat_raw_src = source_tree(&["a.nef", "trip/b.dng"]);
+    config.raw_src = Some(flat_raw_src.path().to_path_buf());
+    config.flat_raw_src = true;
+    let entries = collect_entries(&config).unwrap();
+    assert_eq!(
+        entries[0].raw_paths,
+        vec![flat_raw_src.path().join("a.nef")]
+    );
+    assert!(entries[1].raw_paths.is_empty());
+
+    config.match_raws = false;
+    let entries = collect_entries(&config).unwrap();
+    assert!(entries.iter().all(|entry| entry.raw_paths.is_empty()));
+}
+
+#[test]
+fn raws_are_selected_with_include_raw_only() {
+    let src = source_tree(&["a.jpg", "a.CR2", "b.nef", "c.dng"]);
+    let mut config = config(src.path());
+    config.include_raw_only = true;
+
+    assert_eq!(
+        collected(&config),
+        paths(&["a.CR2", "a.jpg", "b.nef", "c.dng"])
+    );
+
+    // Raws matched to an image stay with it, only the orphans are entries of their own
+    config.match_raws = true;
+    let entries = collect_entries(&config).unwrap();
+    assert_eq!(
+        relative_paths(src.path(), &entries),
+        paths(&["a.jpg", "b.nef", "c.dng"])
+    );
+    assert_eq!(entries[0].raw_paths, vec![src.path().join("a.CR2")]);
+    assert!(entries[1].raw_paths.is_empty());
+}
+
+#[test]
+fn orphan_raws_are_selected() {
+    let src = source_tree(&[
+        "a.jpg",
+        "a.CR2",
+        "b_c.jpg",
+        "b.nef",
+        "c.dng",
+        "trip/c.jpg",
+        "trip/d.ARW",
+    ]);
+    let mut config = config(src.path());
+    config.orphan_raws = true;
+    assert_eq!(collected(&config), paths(&["c.dng", "trip/d.ARW"]));
+
+    // Images left out by the filters still keep their raws from being orphans
+    config.extensions = vec!["png".to_string()];
+    assert_eq!(collected(&config), paths(&["c.dng", "trip/d.ARW"]));
+}
+
+#[test]
+fn raws_are_matched_by_stem() {
+    let src = source_tree(&[
+        "name.jpg",
+        "name.ARW",
+        "name.v2.jpg",
+        "name.v2.dng",
+        "other.v2.jpg",
+        "other.Nef",
+    ]);
+    let mut config = config(src.path());
+    config.match_raws = true;
+    let raw_names = |config: &Config| -> Vec<Vec<PathBuf>> {
+        collect_entries(config)
+            .unwrap()
+            .into_iter()
+            .map(|entry| relative_paths_of(src.path(), &entry.raw_paths))
+            .collect()
+    };
+
+    // Entries are name.jpg, name.v2.jpg and other.v2.jpg
+    assert_eq!(
+        raw_names(&config),
+        vec![paths(&["name.ARW"]), paths(&["name.v2.dng"]), paths(&[])]
+    );
+
+    config.raw_stem = RawStem::Base;
+    assert_eq!(
+        raw_names(&config),
+        vec![
+            paths(&["name.ARW"]),
+            paths(&["name.ARW"]),
+            paths(&["other.Nef"])
+        ]
+    );
+}
+
+#[test]
+fn bursts_are_grouped_by_name() {
+    let src = source_tree(&[
+        "IMG_1234_001.jpg",
+        "IMG_1234_002.jpg",
+        "IMG_1235_001.jpg",
+        "IMG_1236.jpg",
+        "IMG_1237.jpg",
+        "IMG_20240703_120000.jpg",
+        "IMG_20240703_120105.jpg",
+        "trip/IMG_1234_003.jpg",
+    ]);
+    let mut config = config(src.path());
+    let bursts = |config: &Config| -> Vec<Option<PathBuf>> {
+        collect_entries(config)
+            .unwrap()
+            .into_iter()
+            .map(|entry| {
+                entry
+                    .burst
+                    .map(|burst| burst.strip_prefix(src.path()).unwrap().to_path_buf())
+            })
+            .collect()
+    };
+
+    assert!(bursts(&config).iter().all(Option::is_none));
+
+    // Single frames and plain sequential names are not bursts
+    config.group_bursts = true;
+    assert_eq!(
+        bursts(&config),
+        vec![
+            Some(PathBuf::from("IMG_1234")),
+            Some(PathBuf::from("IMG_1234")),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None
+        ]
+    );
+}
+
+#[test]
+fn filter_selects_files_by_embedded_rating() {
+    rexiv2::initialize().unwrap();
+    let src = source_tree(&[
+        "one.tif",
+        "trip/three.tif",
+        "trip/five.tif",
+        "unrated.tif",
+        "zero.tif",
+    ]);
+    for (file, rating) in [
+        ("one.tif", 1),
+        ("trip/three.tif", 3),
+        ("trip/five.tif", 5),
+        ("zero.tif", 0),
+    ] {
+        write_rating(src.path().join(file), DEFAULT_RATING_TAG, rating).unwrap();
+    }
+    let entries = collect_entries(&config(src.path())).unwrap();
+    let selected = |filter: Filter| {
+        let entries: Vec<Entry> = entries
+            .iter()
+            .filter(|entry| evaluate(entry, &filter).unwrap())
+            .cloned()
+            .collect();
+        relative_paths(src.path(), &entries)
+    };
+
+    assert_eq!(
+        selected(filter(3, ComparisonCommand::MoreEqual)),
+        paths(&["trip/five.tif", "trip/three.tif"])
+    );
+    assert_eq!(
+        selected(filter(1, ComparisonCommand::LessEqual)),
+        paths(&["one.tif", "unrated.tif", "zero.tif"])
+    );
+
+    let mut inverse = filter(3, ComparisonCommand::Equal);
+    inverse.inverse = true;
+    assert_eq!(
+        selected(inverse),
+        paths(&["one.tif", "trip/five.tif", "unrated.tif", "zero.tif"])
+    );
+
+    let mut skip_missing = filter(1, ComparisonCommand::LessEqual);
+    skip_missing.treat_missing_as = MissingRating::Skip;
+    assert_eq!(selected(skip_missing), paths(&["one.tif", "zero.tif"]));
+
+    // A rating of 0 is still a rating
+    let mut unrated = filter(5, ComparisonCommand::MoreEqual);
+    unrated.unrated = true;
+    assert_eq!(selected(unrated), paths(&["unrated.tif"]));
+}